@@ -0,0 +1,115 @@
+use std::io;
+
+// One bit per piece, the first piece in the high bit of the first byte as in the
+// Bitfield message. Indexes past the piece count are never set.
+#[derive(Clone, Debug)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+    piece_count: u32
+}
+
+impl Bitfield {
+    pub fn new_empty(piece_count: u32) -> Bitfield {
+        Bitfield {
+            bytes: vec![0; piece_count.div_ceil(8) as usize],
+            piece_count
+        }
+    }
+
+    // Accepts a peer's bitfield if it has exactly one bit per piece and the padding bits of the last byte cleared
+    pub fn from_bytes(bytes: Vec<u8>, piece_count: u32) -> io::Result<Bitfield> {
+        let expected_len = piece_count.div_ceil(8) as usize;
+
+        if bytes.len() != expected_len {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("Bitfield has {} bytes but {} pieces need {}", bytes.len(), piece_count, expected_len)));
+        }
+
+        let spare_bits = expected_len as u32 * 8 - piece_count;
+
+        if let Some(last) = bytes.last() {
+            if last & ((1u16 << spare_bits) - 1) as u8 != 0 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Bitfield has padding bits set"));
+            }
+        }
+
+        Ok(Bitfield { bytes, piece_count })
+    }
+
+    // False for indexes out of range
+    pub fn has(&self, index: u32) -> bool {
+        index < self.piece_count && self.bytes[(index / 8) as usize] & Self::mask(index) != 0
+    }
+
+    // Returns false, leaving the bitfield unchanged, if the index is out of range
+    pub fn set(&mut self, index: u32) -> bool {
+        if index >= self.piece_count {
+            return false;
+        }
+
+        self.bytes[(index / 8) as usize] |= Self::mask(index);
+
+        true
+    }
+
+    pub fn clear(&mut self, index: u32) {
+        if index < self.piece_count {
+            self.bytes[(index / 8) as usize] &= !Self::mask(index);
+        }
+    }
+
+    pub fn count_ones(&self) -> u32 {
+        self.bytes.iter().map(|byte| byte.count_ones()).sum()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.count_ones() == self.piece_count
+    }
+
+    // Indexes of the set bits
+    pub fn pieces(&self) -> Vec<u32> {
+        (0..self.piece_count)
+            .filter(|&index| self.has(index))
+            .collect()
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    fn mask(index: u32) -> u8 {
+        1 << (7 - index % 8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_bitfield_is_accepted() {
+        let bitfield = Bitfield::from_bytes(vec![0b1010_0000, 0b0100_0000], 10).unwrap();
+
+        assert_eq!(bitfield.pieces(), vec![0, 2, 9]);
+        assert!(!bitfield.has(10));
+    }
+
+    #[test]
+    fn bitfield_of_the_wrong_length_is_rejected() {
+        assert!(Bitfield::from_bytes(vec![0xff; 3], 10).is_err());
+        assert!(Bitfield::from_bytes(vec![0xff], 10).is_err());
+    }
+
+    #[test]
+    fn bitfield_with_padding_bits_set_is_rejected() {
+        assert!(Bitfield::from_bytes(vec![0xff, 0b1100_0001], 10).is_err());
+        assert!(Bitfield::from_bytes(vec![0xff, 0b1110_0000], 10).is_err());
+    }
+
+    #[test]
+    fn full_bitfield_has_no_padding() {
+        let bitfield = Bitfield::from_bytes(vec![0xff; 2], 16).unwrap();
+
+        assert!(bitfield.is_complete());
+    }
+}
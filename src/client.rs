@@ -1,79 +1,323 @@
-use std::time::Duration;
-use std::sync::{Mutex, MutexGuard};
-use std::fs::File;
-use std::path::Path;
+use std::time::{Duration, Instant};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::fs::{self, File, OpenOptions};
+use std::io::Read;
+use std::path::{Path, PathBuf};
 use std::{io, fmt};
-use std::env::set_current_dir;
-use std::collections::VecDeque;
+use std::collections::{VecDeque, HashSet, BTreeMap};
+use std::net::SocketAddr;
 use rand::Rng;
+use serde::Serialize;
+use rand::seq::SliceRandom;
 use reqwest::Url;
-use crate::connection::TrackerResponse;
+use reqwest::header::{ACCEPT_ENCODING, CONTENT_ENCODING, HeaderMap};
+use flate2::read::GzDecoder;
+use md5::{Md5, Digest};
+use crate::connection::{TrackerResponse, TrackerFailure, ScrapeResponse, ScrapeStats, PeerActivity};
 use crate::torrent::{Torrent, Piece};
-use crate::utils::url_encode;
+use crate::bitfield::Bitfield;
+use crate::utils::{url_encode, to_hex};
+use crate::sink::{PieceSink, MemorySink, FileSet};
+use crate::config::{DownloadConfig, DownloadOptions};
+use crate::piece_strategy::{PieceStrategy, PieceOrder};
 
 pub struct Client {
     pub id: Vec<u8>,
     pub port: u16,
-    pub uploaded: u32,
-    pub downloaded: u32,
+    uploaded: AtomicU64, // bytes sent to peers, reported to the tracker
     pub torrent: TorrentState,
-    file: Mutex<File>,
+    pub config: DownloadConfig,
+    pub out_path: PathBuf, // where the download is saved, empty when it isn't saved to a file
+    partial_files: Vec<PathBuf>, // final paths of the files saved with `.part` until the download completes
+    sink: Box<dyn PieceSink>,
+    write_cache: Mutex<WriteCache>,
+    rate: Mutex<RateMeter>,
+    counters: Counters,
+    traffic: Arc<Traffic>,
+    shutdown: Arc<AtomicBool>,
+    done: Arc<AtomicBool>, // set with the last piece, wakes up the workers waiting on their peer
+    peers: Mutex<HashSet<SocketAddr>>, // peers with an open or in progress connection
+    unchoked_peers: AtomicUsize, // connected peers that let us request pieces
+    activity: Mutex<Vec<Weak<PeerActivity>>>, // of the open connections, for the idle checks
+    tracker_id: Mutex<Option<Vec<u8>>> // last one the tracker gave, some trackers need it back
+}
+
+// Finished pieces waiting to be written, flushed in offset order once `capacity` bytes are held
+struct WriteCache {
+    pieces: BTreeMap<u64, Vec<u8>>, // keyed by offset in the file
+    size: usize,
+    capacity: usize
+}
+
+// Bytes done sampled over the last `WINDOW`, the rate is averaged over it so the ETA doesn't jump around
+struct RateMeter {
+    samples: VecDeque<(Instant, u64)>
+}
+
+// Running totals updated by the workers, read with `Client::stats`
+#[derive(Default)]
+struct Counters {
+    blocks_requested: AtomicU64,
+    blocks_received: AtomicU64,
+    blocks_rejected: AtomicU64,
+    pieces_failed: AtomicU64,
+    wrong_swarm_peers: AtomicU64
+}
+
+// Bytes sent and received on the peer connections, handshakes and message framing
+// included, updated by every `Connection` of the client. Web seed downloads count as received.
+#[derive(Default)]
+pub struct Traffic {
+    sent: AtomicU64,
+    received: AtomicU64
+}
+
+#[derive(Clone, Copy)]
+pub enum Counter {
+    BlockRequested,
+    BlockReceived,
+    BlockRejected, // rejected by the peer or timed out
+    PieceFailed, // didn't match its hash
+    WrongSwarmPeer // answered the handshake with another info_hash
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Stats {
+    pub blocks_requested: u64,
+    pub blocks_received: u64,
+    pub blocks_rejected: u64,
+    pub pieces_failed: u64,
+    pub wrong_swarm_peers: u64
+}
+
+// Progress snapshot, printed as a JSON line by `--json`
+#[derive(Serialize)]
+pub struct Status {
+    pub name: String,
+    pub done: u32, // pieces
+    pub total: u32,
+    pub peers: usize,
+    pub rate: u64 // bytes per second
+}
+
+// A downloaded file whose MD5 differs from the md5sum in the torrent
+#[derive(Debug)]
+pub struct Md5Mismatch {
+    pub path: String,
+    pub expected: String,
+    pub actual: String
+}
+
+#[derive(Clone, Copy)]
+pub enum TrackerEvent {
+    Started,
+    Stopped
 }
 
 pub struct TorrentState {
     pub info_hash: Vec<u8>,
     pub total_pieces: u32,
+    pub length: u64,
     piece_queue: Mutex<VecDeque<Piece>>,
     done_pieces: Mutex<u32>,
+    done_bytes: Mutex<u64>,
+    completed: Mutex<Bitfield>,
+    failures: Mutex<Vec<u32>>, // hash check failures, indexed by piece
+    bad_piece: Mutex<Option<u32>>, // piece that failed too many times, the download is aborted
+    wanted: Vec<bool>, // indexed by piece, false for pieces of files not selected
+    pub strategy: Box<dyn PieceStrategy>
 }
 
 impl Client {
     const PORT: u16 = 6881;
+    const TRACKER_TIMEOUT: Duration = Duration::from_secs(15);
+
+    pub fn new(torrent: &Torrent, options: &DownloadOptions, config: DownloadConfig) -> io::Result<Client> {
+        let out_path = options.out_dir.join(options.name_override.as_deref().unwrap_or(&torrent.name));
+        let (sink, paths) = Self::create_files(torrent, &out_path, options.part_suffix, &config)?;
 
-    pub fn new<P: AsRef<Path>>(torrent: &Torrent, out_path: Option<P>) -> Client {
-        let file = Self::create_files(torrent, out_path).unwrap();
+        Ok(Client {
+            out_path,
+            partial_files: if options.part_suffix { paths } else { Vec::new() },
+            ..Self::with_sink(torrent, sink, config)
+        })
+    }
+
+    // Keeps the download in memory instead of a file, the data is read back with
+    // `take_contents`. Fails for torrents bigger than `config.max_memory_size`.
+    pub fn new_in_memory(torrent: &Torrent, config: DownloadConfig) -> io::Result<Client> {
+        if torrent.total_length() > config.max_memory_size {
+            return Err(io::Error::new(io::ErrorKind::OutOfMemory,
+                                      format!("Torrent takes {} bytes, more than the {} allowed in memory",
+                                              torrent.total_length(), config.max_memory_size)));
+        }
+
+        let sink = MemorySink::new(torrent.total_length() as usize);
+
+        Ok(Self::with_sink(torrent, Box::new(sink), config))
+    }
 
+    // Stores the download in a custom backend, e.g. remote or encrypted storage
+    pub fn with_sink(torrent: &Torrent, sink: Box<dyn PieceSink>, config: DownloadConfig) -> Client {
         Client {
-            id: Self::generate_random_id(),
+            id: config.peer_id.clone().unwrap_or_else(Self::generate_random_id),
             port: Self::PORT,
-            uploaded: 0,
-            downloaded: 0,
-            file: Mutex::new(file),
-            torrent: TorrentState::new(torrent)
+            uploaded: AtomicU64::new(0),
+            sink,
+            out_path: PathBuf::new(),
+            partial_files: Vec::new(),
+            write_cache: Mutex::new(WriteCache::new(config.write_cache_size)),
+            rate: Mutex::new(RateMeter::new()),
+            counters: Counters::default(),
+            traffic: Arc::new(Traffic::default()),
+            torrent: TorrentState::new(torrent, config.files.as_deref(), config.piece_order),
+            config,
+            shutdown: Arc::new(AtomicBool::new(false)),
+            done: Arc::new(AtomicBool::new(false)),
+            peers: Mutex::new(HashSet::new()),
+            unchoked_peers: AtomicUsize::new(0),
+            activity: Mutex::new(Vec::new()),
+            tracker_id: Mutex::new(None)
         }
     }
 
-    pub fn get_done_pieces(&self) -> MutexGuard<u32> {
+    pub fn get_done_pieces(&self) -> MutexGuard<'_, u32> {
         self.torrent.done_pieces
             .lock()
             .unwrap()
     }
 
-    pub fn get_piece_queue(&self) -> MutexGuard<VecDeque<Piece>> {
+    pub fn get_done_bytes(&self) -> MutexGuard<'_, u64> {
+        self.torrent.done_bytes
+            .lock()
+            .unwrap()
+    }
+
+    pub fn get_piece_queue(&self) -> MutexGuard<'_, VecDeque<Piece>> {
         self.torrent
             .piece_queue
             .lock()
             .unwrap()
     }
 
-    pub fn get_file(&self) -> MutexGuard<File> {
-        self.file.lock().unwrap()
+    // The downloaded data when it's kept in memory, None when it's saved to a file
+    pub fn take_contents(&self) -> io::Result<Option<Vec<u8>>> {
+        self.flush_write_cache()?;
+
+        Ok(self.sink.take_contents())
+    }
+
+    pub fn get_shutdown_flag(&self) -> Arc<AtomicBool> {
+        self.shutdown.clone()
     }
 
-    pub fn get_tracker(&self, torrent: &Torrent) -> Result<TrackerResponse, TrackerError> {
+    // Whether `max_download` bytes were received, then no new piece is started
+    pub fn is_over_quota(&self) -> bool {
+        self.config.max_download.is_some_and(|max| self.traffic.received() >= max)
+    }
+
+    // Pieces taken by a worker or web seed but not finished yet
+    pub fn pieces_in_progress(&self) -> usize {
+        let missing = self.torrent.missing_pieces().len();
+
+        missing.saturating_sub(self.get_piece_queue().len())
+    }
+
+    pub fn get_traffic(&self) -> Arc<Traffic> {
+        self.traffic.clone()
+    }
+
+    pub fn get_done_flag(&self) -> Arc<AtomicBool> {
+        self.done.clone()
+    }
+
+    pub fn set_done(&self) {
+        self.done.store(true, Ordering::Relaxed);
+    }
+
+    pub fn send_tracker_request(&self, torrent: &Torrent, event: Option<TrackerEvent>) -> Result<TrackerResponse, TrackerError> {
+        self.announce(torrent, event, self.numwant())
+    }
+
+    // Re-announces asking for a full `numwant` of peers even when we have that many,
+    // to replace peers that stopped sending us anything
+    pub fn request_fresh_peers(&self, torrent: &Torrent) -> Result<TrackerResponse, TrackerError> {
+        self.announce(torrent, None, self.config.numwant)
+    }
+
+    fn announce(&self, torrent: &Torrent, event: Option<TrackerEvent>, numwant: u32) -> Result<TrackerResponse, TrackerError> {
         let mut buf = Vec::new();
-        let url = self.parse_url(&torrent);
+        let url = self.parse_url(torrent, event, numwant)?;
         let req_client = reqwest::blocking::Client::builder()
-            .timeout(Duration::from_secs(15))
+            .timeout(Self::TRACKER_TIMEOUT)
             .build()?;
         let mut res = req_client.get(url)
+            .header(ACCEPT_ENCODING, "gzip")
             .send()?;
 
         res.copy_to(&mut buf)?;
 
-        let tracker_response = serde_bencode::from_bytes::<TrackerResponse>(&buf.as_slice())?;
+        let body = Self::decode_body(res.headers(), buf)?;
 
-        Ok(tracker_response)
+        self.parse_tracker_response(&body)
+    }
+
+    // Same as `send_tracker_request` but doesn't block a thread, so several trackers can be announced to concurrently.
+    // It must be polled inside a tokio 0.2 runtime, the one reqwest 0.10 runs on.
+    pub async fn send_tracker_request_async(&self, torrent: &Torrent, event: Option<TrackerEvent>) -> Result<TrackerResponse, TrackerError> {
+        let url = self.parse_url(torrent, event, self.numwant())?;
+        let req_client = reqwest::Client::builder()
+            .timeout(Self::TRACKER_TIMEOUT)
+            .build()?;
+        let res = req_client.get(url)
+            .header(ACCEPT_ENCODING, "gzip")
+            .send()
+            .await?;
+        let headers = res.headers().clone();
+        let buf = res.bytes().await?;
+        let body = Self::decode_body(&headers, buf.to_vec())?;
+
+        self.parse_tracker_response(&body)
+    }
+
+    // Asks the tracker for the swarm counts without joining the swarm (BEP 48)
+    pub fn scrape(torrent: &Torrent) -> Result<ScrapeStats, TrackerError> {
+        let mut buf = Vec::new();
+        let url = Self::scrape_url(torrent).ok_or(TrackerError::ScrapeUnsupported)?;
+        let req_client = reqwest::blocking::Client::builder()
+            .timeout(Self::TRACKER_TIMEOUT)
+            .build()?;
+        let mut res = req_client.get(&url)
+            .header(ACCEPT_ENCODING, "gzip")
+            .send()?;
+
+        res.copy_to(&mut buf)?;
+
+        let body = Self::decode_body(res.headers(), buf)?;
+
+        if let Ok(failure) = serde_bencode::from_bytes::<TrackerFailure>(&body) {
+            return Err(TrackerError::TrackerFailure(failure.reason));
+        }
+
+        serde_bencode::from_bytes::<ScrapeResponse>(&body)?
+            .files
+            .remove(serde_bytes::Bytes::new(&torrent.info_hash))
+            .ok_or(TrackerError::NotTracked)
+    }
+
+    // The scrape URL is the announce URL with `announce` at the start of its last
+    // path segment replaced by `scrape`, trackers whose URL doesn't have it can't be scraped
+    fn scrape_url(torrent: &Torrent) -> Option<String> {
+        let (path, query) = match torrent.announce.split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (torrent.announce.as_str(), None)
+        };
+        let (base, segment) = path.rsplit_once('/')?;
+        let rest = segment.strip_prefix("announce")?;
+        let query = query.map_or_else(String::new, |query| format!("{}&", query));
+
+        Some(format!("{}/scrape{}?{}info_hash={}", base, rest, query, url_encode(&torrent.info_hash)))
     }
 
     pub fn is_done(&self) -> bool {
@@ -88,48 +332,727 @@ impl Client {
         false
     }
 
-    fn parse_url(&self, torrent: &Torrent) -> Url {
+    // Fraction of pieces downloaded, between 0 and 1
+    pub fn progress(&self) -> f64 {
+        if self.torrent.total_pieces == 0 {
+            return 1.0;
+        }
+
+        *self.get_done_pieces() as f64 / self.torrent.total_pieces as f64
+    }
+
+    // Bytes of verified pieces written, the last piece counts only its real size
+    pub fn bytes_done(&self) -> u64 {
+        *self.get_done_bytes()
+    }
+
+    // Bytes of the selected files still to download
+    pub fn bytes_left(&self) -> u64 {
+        self.torrent.length.saturating_sub(self.bytes_done())
+    }
+
+    pub fn uploaded(&self) -> u64 {
+        self.uploaded.load(Ordering::Relaxed)
+    }
+
+    pub fn add_uploaded(&self, bytes: u64) {
+        self.uploaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    // Samples the bytes done for the download rate, called when a piece finishes
+    pub fn record_progress(&self) {
+        let bytes_done = self.bytes_done();
+
+        self.rate.lock().unwrap().record(bytes_done);
+    }
+
+    // Download rate in bytes per second, averaged over the last seconds
+    pub fn overall_rate(&self) -> f64 {
+        self.rate.lock().unwrap().rate()
+    }
+
+    // Time left at the current rate, None while nothing is being downloaded
+    pub fn eta(&self) -> Option<Duration> {
+        RateMeter::eta(self.bytes_left(), self.overall_rate())
+    }
+
+    pub fn count(&self, counter: Counter, n: u64) {
+        let counter = match counter {
+            Counter::BlockRequested => &self.counters.blocks_requested,
+            Counter::BlockReceived => &self.counters.blocks_received,
+            Counter::BlockRejected => &self.counters.blocks_rejected,
+            Counter::PieceFailed => &self.counters.pieces_failed,
+            Counter::WrongSwarmPeer => &self.counters.wrong_swarm_peers
+        };
+
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            blocks_requested: self.counters.blocks_requested.load(Ordering::Relaxed),
+            blocks_received: self.counters.blocks_received.load(Ordering::Relaxed),
+            blocks_rejected: self.counters.blocks_rejected.load(Ordering::Relaxed),
+            pieces_failed: self.counters.pieces_failed.load(Ordering::Relaxed),
+            wrong_swarm_peers: self.counters.wrong_swarm_peers.load(Ordering::Relaxed)
+        }
+    }
+
+    // Returns false if we are already connected or connecting to this peer
+    pub fn add_peer(&self, addr: SocketAddr) -> bool {
+        self.peers.lock().unwrap().insert(addr)
+    }
+
+    pub fn remove_peer(&self, addr: &SocketAddr) {
+        self.peers.lock().unwrap().remove(addr);
+    }
+
+    pub fn status(&self, name: &str) -> Status {
+        Status {
+            name: name.to_string(),
+            done: *self.get_done_pieces(),
+            total: self.torrent.total_pieces,
+            peers: self.peer_count(),
+            rate: self.overall_rate() as u64
+        }
+    }
+
+    // Fraction of every file downloaded, by path starting with the torrent name.
+    // A file is ready to use once it reaches 1.0, even if the download goes on.
+    pub fn file_progress(&self, torrent: &Torrent) -> Vec<(String, f64)> {
+        let completed = self.torrent.completed_pieces()
+            .into_iter()
+            .map(|index| torrent.piece_range(index))
+            .collect::<Vec<(u64, u64)>>();
+
+        torrent.files()
+            .into_iter()
+            .map(|file| {
+                let done = completed.iter()
+                    .map(|&(begin, end)| end.min(file.end()).saturating_sub(begin.max(file.offset)))
+                    .sum::<u64>();
+                let progress = if file.length > 0 {
+                    done as f64 / file.length as f64
+                } else {
+                    1.0
+                };
+
+                (file.path.display().to_string(), progress)
+            })
+            .collect()
+    }
+
+    pub fn peer_count(&self) -> usize {
+        self.peers.lock().unwrap().len()
+    }
+
+    // Called by the workers when a peer chokes or unchokes us, only on changes
+    pub fn set_unchoked(&self, unchoked: bool) {
+        if unchoked {
+            self.unchoked_peers.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.unchoked_peers.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn watch_activity(&self, activity: &Arc<PeerActivity>) {
+        self.activity.lock().unwrap().push(Arc::downgrade(activity));
+    }
+
+    // Pings the idle peers and drops the dead ones, called periodically by the supervisor
+    // so a connection is checked even while its worker isn't reading from it
+    pub fn check_idle_peers(&self) {
+        let open = {
+            let mut activity = self.activity.lock().unwrap();
+
+            // closed connections drop their side
+            activity.retain(|peer| peer.strong_count() > 0);
+            activity.iter().filter_map(Weak::upgrade).collect::<Vec<_>>()
+        };
+
+        for peer in open {
+            peer.check();
+        }
+    }
+
+    pub fn unchoked_peers(&self) -> usize {
+        self.unchoked_peers.load(Ordering::Relaxed)
+    }
+
+    pub fn shutdown(&self) {
+        self.shutdown.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutdown.load(Ordering::Relaxed)
+    }
+
+    // Reads the whole output back and returns the indexes of the pieces whose hash doesn't match
+    pub fn verify_file(&self, torrent: &Torrent) -> io::Result<Vec<u32>> {
+        self.flush_write_cache()?;
+
+        let mut bad_pieces = Vec::new();
+
+        for piece in torrent.create_piece_queue() {
+            if !self.torrent.is_wanted(piece.index) {
+                continue;
+            }
+
+            if !self.is_piece_intact(&piece)? {
+                bad_pieces.push(piece.index);
+            }
+        }
+
+        Ok(bad_pieces)
+    }
+
+    // Hashes every fully downloaded file that has an md5sum in the torrent and
+    // returns the ones that don't match. Files not selected with `files` are skipped.
+    pub fn verify_md5sums(&self, torrent: &Torrent) -> io::Result<Vec<Md5Mismatch>> {
+        const CHUNK_SIZE: u64 = 1024 * 1024;
+
+        self.flush_write_cache()?;
+
+        let files = torrent.files()
+            .into_iter()
+            .zip(torrent.file_md5sums())
+            .zip(self.file_progress(torrent));
+        let mut mismatches = Vec::new();
+
+        for ((file, md5sum), (path, progress)) in files {
+            let expected = match md5sum {
+                Some(md5sum) if progress >= 1.0 => md5sum,
+                _ => continue
+            };
+            let mut hasher = Md5::new();
+            let mut offset = file.offset;
+
+            while offset < file.end() {
+                let mut buf = vec![0; CHUNK_SIZE.min(file.end() - offset) as usize];
+
+                self.sink.read_at(&mut buf, offset)?;
+                hasher.input(&buf);
+                offset += buf.len() as u64;
+            }
+
+            let actual = to_hex(&hasher.result());
+
+            if !actual.eq_ignore_ascii_case(expected.trim()) {
+                mismatches.push(Md5Mismatch { path, expected, actual });
+            }
+        }
+
+        Ok(mismatches)
+    }
+
+    // Re-hashes up to `count` random completed pieces to catch corruption on disk.
+    // The corrupted ones are queued to be downloaded again, their indexes are returned.
+    pub fn recheck_pieces(&self, pieces: &[Piece], count: usize) -> io::Result<Vec<u32>> {
+        // completed before flushing, so none of them is still in the write cache
+        let completed = self.torrent.completed_pieces();
+        let sample = completed.choose_multiple(&mut rand::thread_rng(), count);
+        let mut corrupted = Vec::new();
+
+        self.flush_write_cache()?;
+
+        for &index in sample {
+            let piece = &pieces[index as usize];
+
+            if !self.is_piece_intact(piece)? && self.requeue_piece(piece.clone()) {
+                corrupted.push(index);
+            }
+        }
+
+        Ok(corrupted)
+    }
+
+    // Reads the piece back from the output file and checks its hash
+    fn is_piece_intact(&self, piece: &Piece) -> io::Result<bool> {
+        let mut buf = vec![0; piece.length as usize];
+
+        self.sink.read_at(&mut buf, piece.begin)?;
+
+        Ok(piece.verify(&buf).is_ok())
+    }
+
+    // Undoes the completion of a piece so it's downloaded again. Once the download
+    // is done the workers are gone, so it's left to the final verification instead.
+    fn requeue_piece(&self, piece: Piece) -> bool {
+        let mut done_pieces = self.get_done_pieces();
+
+        if *done_pieces >= self.torrent.total_pieces {
+            return false;
+        }
+
+        *done_pieces -= 1;
+        *self.get_done_bytes() -= piece.length as u64;
+        self.torrent.set_missing(piece.index);
+        self.get_piece_queue().push_back(piece);
+
+        true
+    }
+
+    // Picks up a previous download of the output file: the queued pieces already in
+    // it with the right hash are counted as done. Returns how many pieces were kept.
+    pub fn resume(&self) -> io::Result<u32> {
+        let queued = self.get_piece_queue().clone();
+        let mut intact = HashSet::new();
+
+        for piece in &queued {
+            match self.is_piece_intact(piece) {
+                Ok(true) => {
+                    intact.insert(piece.index);
+                },
+                Ok(false) => {},
+                // the file was cut short, the pieces past its end are missing
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {},
+                Err(e) => return Err(e)
+            }
+        }
+
+        for piece in queued.iter().filter(|piece| intact.contains(&piece.index)) {
+            *self.get_done_pieces() += 1;
+            *self.get_done_bytes() += piece.length as u64;
+            self.torrent.set_completed(piece.index);
+        }
+
+        self.get_piece_queue().retain(|piece| !intact.contains(&piece.index));
+
+        if *self.get_done_pieces() >= self.torrent.total_pieces {
+            self.set_done();
+        }
+
+        Ok(intact.len() as u32)
+    }
+
+    // Stores a verified piece and counts it as done, returns how many pieces are done
+    pub fn complete_piece(&self, piece: &Piece, data: Vec<u8>) -> io::Result<u32> {
+        self.write_piece(piece.begin, data)?;
+
+        let mut done_pieces = self.get_done_pieces();
+
+        *done_pieces += 1;
+        *self.get_done_bytes() += piece.length as u64;
+
+        if *done_pieces >= self.torrent.total_pieces {
+            self.set_done();
+        }
+
+        self.torrent.set_completed(piece.index);
+        self.record_progress();
+
+        Ok(*done_pieces)
+    }
+
+    // Writes a finished piece at `begin`, or keeps it in the write cache if enabled.
+    // Pieces never overlap, so workers can write concurrently without a lock.
+    pub fn write_piece(&self, begin: u64, data: Vec<u8>) -> io::Result<()> {
+        if self.config.write_cache_size == 0 {
+            return self.sink.write_at(&data, begin);
+        }
+
+        let mut cache = self.write_cache.lock().unwrap();
+
+        cache.size += data.len();
+        cache.pieces.insert(begin, data);
+
+        if cache.size >= cache.capacity {
+            cache.flush(self.sink.as_ref())?;
+        }
+
+        Ok(())
+    }
+
+    // Reads back a completed piece, from the write cache if it wasn't written yet
+    pub fn read_piece(&self, begin: u64, length: usize) -> io::Result<Vec<u8>> {
+        let cache = self.write_cache.lock().unwrap();
+
+        if let Some(data) = cache.pieces.get(&begin) {
+            return Ok(data.clone());
+        }
+
+        let mut buf = vec![0; length];
+
+        self.sink.read_at(&mut buf, begin)?;
+
+        Ok(buf)
+    }
+
+    pub fn flush_write_cache(&self) -> io::Result<()> {
+        let mut cache = self.write_cache.lock().unwrap();
+
+        cache.flush(self.sink.as_ref())
+    }
+
+    // Drops the `.part` suffix of the files once the download is complete
+    pub fn finish_files(&self) -> io::Result<()> {
+        self.sync_file()?;
+
+        for path in &self.partial_files {
+            fs::rename(Self::part_path(path), path)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn sync_file(&self) -> io::Result<()> {
+        self.flush_write_cache()?;
+
+        self.sink.sync()
+    }
+
+    fn parse_url(&self, torrent: &Torrent, event: Option<TrackerEvent>, numwant: u32) -> Result<Url, TrackerError> {
         let url_hash = url_encode(&self.torrent.info_hash);
         let url_peer_id = url_encode(&self.id);
-        let base_url = format!("{}?info_hash={}&peer_id={}", torrent.announce, url_hash, url_peer_id);
-        let url_params = [
+        // the announce URL may already carry a query, e.g. a private tracker passkey
+        let separator = if torrent.announce.contains('?') { '&' } else { '?' };
+        let mut base_url = format!("{}{}info_hash={}&peer_id={}", torrent.announce, separator, url_hash, url_peer_id);
+
+        // can be binary like the info_hash, so it's encoded the same way
+        if let Some(tracker_id) = &*self.tracker_id.lock().unwrap() {
+            base_url.push_str(&format!("&trackerid={}", url_encode(tracker_id)));
+        }
+
+        let mut url_params = vec![
             ("port", self.port.to_string()),
-            ("uploaded", self.uploaded.to_string()),
-            ("downloaded", self.downloaded.to_string()),
-            ("compact", "1".to_string()),
-            ("left", torrent.length.to_string())
+            ("uploaded", self.uploaded().to_string()),
+            ("downloaded", self.bytes_done().to_string()),
+            ("compact", u8::from(self.config.compact).to_string()),
+            ("left", self.bytes_left().to_string()),
+            ("numwant", numwant.to_string())
         ];
-        let url = Url::parse_with_params(base_url.as_str(),&url_params).unwrap();
 
-        url
+        if let Some(event) = event {
+            url_params.push(("event", event.to_string()));
+        }
+
+        // lets dual stack trackers hand our IPv6 address to other peers
+        if let Some(ipv6) = self.config.announce_ipv6 {
+            url_params.push(("ipv6", ipv6.to_string()));
+        }
+
+        Url::parse_with_params(base_url.as_str(), &url_params)
+            .map_err(|e| TrackerError::BadAnnounceUrl(torrent.announce.clone(), e.to_string()))
+    }
+
+    // Some trackers gzip the response since we accept it, reqwest is built without decompression
+    fn decode_body(headers: &HeaderMap, body: Vec<u8>) -> Result<Vec<u8>, TrackerError> {
+        let gzipped = headers.get(CONTENT_ENCODING)
+            .and_then(|encoding| encoding.to_str().ok())
+            .is_some_and(|encoding| encoding.trim().eq_ignore_ascii_case("gzip"));
+
+        if !gzipped {
+            return Ok(body);
+        }
+
+        let mut decoded = Vec::new();
+
+        GzDecoder::new(&body[..]).read_to_end(&mut decoded).map_err(TrackerError::DecodeError)?;
+
+        Ok(decoded)
+    }
+
+    // Once we have as many peers as we asked for, re-announces don't ask for more
+    fn numwant(&self) -> u32 {
+        if self.peer_count() >= self.config.numwant as usize {
+            return 0;
+        }
+
+        self.config.numwant
+    }
+
+    // Remembers the tracker id, a response without one keeps the previous
+    fn parse_tracker_response(&self, buf: &[u8]) -> Result<TrackerResponse, TrackerError> {
+        if let Ok(failure) = serde_bencode::from_bytes::<TrackerFailure>(buf) {
+            return Err(TrackerError::TrackerFailure(failure.reason));
+        }
+
+        let tracker_response = serde_bencode::from_bytes::<TrackerResponse>(buf)?;
+
+        if let Some(tracker_id) = &tracker_response.tracker_id {
+            *self.tracker_id.lock().unwrap() = Some(tracker_id.to_vec());
+        }
+
+        Ok(tracker_response)
     }
 
     fn generate_random_id() -> Vec<u8> {
         rand::thread_rng().gen::<[u8; 20]>().to_vec()
     }
 
-    // TODO: add multiple files creation
-    fn create_files<P: AsRef<Path>>(torrent: &Torrent, path: Option<P>) -> io::Result<File> {
-        match path {
-            Some(path) => set_current_dir(path)?,
-            None => {}
+    // A single file is saved at `path`, a multi-file torrent's files under the `path`
+    // directory, with their paths in the torrent minus the torrent name.
+    // Returns the sink writing to them along with their final paths.
+    fn create_files(torrent: &Torrent, path: &Path, part_suffix: bool, config: &DownloadConfig) -> io::Result<(Box<dyn PieceSink>, Vec<PathBuf>)> {
+        let files = torrent.files();
+        let paths = if torrent.is_multi_file() {
+            files.iter()
+                .map(|file| path.join(file.path.iter().skip(1).collect::<PathBuf>()))
+                .collect()
+        } else {
+            vec![path.to_path_buf()]
+        };
+        let mut handles = Vec::new();
+
+        for (file, file_path) in files.iter().zip(&paths) {
+            let file_path = if part_suffix { Self::part_path(file_path) } else { file_path.clone() };
+
+            handles.push((Self::create_file(&file_path, file.length, config.preallocate, config.resume)?, file.offset..file.end()));
         }
 
-        let file = File::create(&torrent.name)?;
+        let sink: Box<dyn PieceSink> = if torrent.is_multi_file() {
+            Box::new(FileSet::new(handles))
+        } else {
+            Box::new(handles.remove(0).0)
+        };
+
+        Ok((sink, paths))
+    }
+
+    fn part_path(path: &Path) -> PathBuf {
+        let mut part_path = path.as_os_str().to_owned();
 
-        file.set_len(torrent.length)?;
+        part_path.push(".part");
+        PathBuf::from(part_path)
+    }
+
+    // When resuming, an existing file is kept unless it's longer than it should be,
+    // then it can't be a previous download of it and is started over
+    fn create_file(path: &Path, length: u64, preallocate: bool, resume: bool) -> io::Result<File> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let keep = resume && fs::metadata(path).is_ok_and(|metadata| metadata.len() <= length);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(!keep)
+            .open(path)?;
+
+        // `set_len` only extends the file size, it creates a sparse file on most
+        // Linux, macOS and Windows (NTFS) filesystems and writes zeros on FAT
+        if preallocate {
+            file.set_len(length)?;
+        }
 
         Ok(file)
     }
 }
 
+impl Traffic {
+    pub fn add_sent(&self, bytes: u64) {
+        self.sent.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_received(&self, bytes: u64) {
+        self.received.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    // Sent over received, None until something is received
+    pub fn ratio(&self) -> Option<f64> {
+        match self.received() {
+            0 => None,
+            received => Some(self.sent() as f64 / received as f64)
+        }
+    }
+}
+
+impl WriteCache {
+    fn new(capacity: usize) -> WriteCache {
+        WriteCache {
+            pieces: BTreeMap::new(),
+            size: 0,
+            capacity
+        }
+    }
+
+    // Contiguous pieces are joined so they are written with a single call
+    fn flush(&mut self, sink: &dyn PieceSink) -> io::Result<()> {
+        let mut batch = Vec::new();
+        let mut batch_begin = 0;
+
+        for (begin, data) in std::mem::take(&mut self.pieces) {
+            if !batch.is_empty() && batch_begin + batch.len() as u64 != begin {
+                sink.write_at(&batch, batch_begin)?;
+                batch.clear();
+            }
+
+            if batch.is_empty() {
+                batch_begin = begin;
+            }
+
+            batch.extend(data);
+        }
+
+        if !batch.is_empty() {
+            sink.write_at(&batch, batch_begin)?;
+        }
+
+        self.size = 0;
+
+        Ok(())
+    }
+}
+
+impl RateMeter {
+    const WINDOW: Duration = Duration::from_secs(20);
+
+    fn new() -> RateMeter {
+        RateMeter {
+            samples: VecDeque::from(vec![(Instant::now(), 0)])
+        }
+    }
+
+    fn record(&mut self, bytes_done: u64) {
+        self.record_at(Instant::now(), bytes_done);
+    }
+
+    fn record_at(&mut self, now: Instant, bytes_done: u64) {
+        self.samples.push_back((now, bytes_done));
+
+        // the oldest sample is the base of the average, one is always kept
+        while self.samples.len() > 1 && now.duration_since(self.samples[0].0) > Self::WINDOW {
+            self.samples.pop_front();
+        }
+    }
+
+    // Measured up to now instead of the last sample, so the rate drops while stalled
+    fn rate(&self) -> f64 {
+        self.rate_at(Instant::now())
+    }
+
+    fn rate_at(&self, now: Instant) -> f64 {
+        let (oldest_time, oldest_bytes) = self.samples[0];
+        let (_, newest_bytes) = self.samples[self.samples.len() - 1];
+        let elapsed = now.saturating_duration_since(oldest_time).as_secs_f64();
+
+        if elapsed == 0.0 {
+            return 0.0;
+        }
+
+        // bytes done go down when a corrupted piece is downloaded again
+        newest_bytes.saturating_sub(oldest_bytes) as f64 / elapsed
+    }
+
+    // Time to download `remaining` bytes at `rate`, None while nothing is being downloaded
+    fn eta(remaining: u64, rate: f64) -> Option<Duration> {
+        if rate <= 0.0 {
+            return None;
+        }
+
+        Some(Duration::from_secs_f64(remaining as f64 / rate))
+    }
+}
+
 impl TorrentState {
-    fn new(torrent: &Torrent) -> TorrentState {
+    fn new(torrent: &Torrent, files: Option<&[usize]>, piece_order: PieceOrder) -> TorrentState {
+        let wanted = match files {
+            Some(files) => torrent.wanted_pieces(files),
+            None => vec![true; torrent.piece_count() as usize]
+        };
+        let piece_queue = torrent.create_piece_queue()
+            .into_iter()
+            .filter(|piece| wanted[piece.index as usize])
+            .collect::<VecDeque<Piece>>();
+
         TorrentState {
             done_pieces: Mutex::new(0),
-            piece_queue: Mutex::new(torrent.create_piece_queue()),
-            total_pieces: torrent.pieces.len() as u32,
+            done_bytes: Mutex::new(0),
+            completed: Mutex::new(Bitfield::new_empty(torrent.piece_count())),
+            failures: Mutex::new(vec![0; torrent.piece_count() as usize]),
+            bad_piece: Mutex::new(None),
+            total_pieces: piece_queue.len() as u32,
+            length: piece_queue.iter().map(|piece| piece.length as u64).sum(),
+            piece_queue: Mutex::new(piece_queue),
             info_hash: torrent.info_hash.to_owned(),
+            strategy: piece_order.strategy(torrent.piece_count()),
+            wanted
+        }
+    }
+
+    // Pieces in the torrent, selected or not
+    pub fn piece_count(&self) -> u32 {
+        self.wanted.len() as u32
+    }
+
+    pub fn is_wanted(&self, index: u32) -> bool {
+        self.wanted[index as usize]
+    }
+
+    // Counts a hash check failure of the piece, returns how many it has had
+    pub fn add_failure(&self, index: u32) -> u32 {
+        let mut failures = self.failures.lock().unwrap();
+
+        failures[index as usize] += 1;
+        failures[index as usize]
+    }
+
+    pub fn set_bad_piece(&self, index: u32) {
+        self.bad_piece.lock().unwrap().get_or_insert(index);
+    }
+
+    pub fn bad_piece(&self) -> Option<u32> {
+        *self.bad_piece.lock().unwrap()
+    }
+
+    pub fn is_completed(&self, index: u32) -> bool {
+        self.completed.lock().unwrap().has(index)
+    }
+
+    pub fn set_completed(&self, index: u32) {
+        self.completed.lock().unwrap().set(index);
+    }
+
+    pub fn set_missing(&self, index: u32) {
+        self.completed.lock().unwrap().clear(index);
+    }
+
+    // Indexes of the wanted pieces not downloaded yet, including the ones in progress
+    pub fn missing_pieces(&self) -> Vec<u32> {
+        self.pieces_where(false)
+    }
+
+    pub fn completed_pieces(&self) -> Vec<u32> {
+        self.pieces_where(true)
+    }
+
+    fn pieces_where(&self, completed: bool) -> Vec<u32> {
+        let done = self.completed.lock().unwrap();
+
+        (0..self.wanted.len() as u32)
+            .filter(|&index| done.has(index) == completed && self.wanted[index as usize])
+            .collect()
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Blocks requested: {}, received: {}, rejected or timed out: {}. Pieces failing their hash: {}. Peers of another torrent: {}",
+               self.blocks_requested, self.blocks_received, self.blocks_rejected, self.pieces_failed, self.wrong_swarm_peers)
+    }
+}
+
+impl fmt::Display for Md5Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} doesn't match its md5sum, expected {} but got {}", self.path, self.expected, self.actual)
+    }
+}
+
+impl fmt::Display for TrackerEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Started => write!(f, "started"),
+            Self::Stopped => write!(f, "stopped")
         }
     }
 }
@@ -137,7 +1060,12 @@ impl TorrentState {
 #[derive(Debug)]
 pub enum TrackerError {
     SerializationError(serde_bencode::Error),
-    RequestError(reqwest::Error)
+    RequestError(reqwest::Error),
+    DecodeError(io::Error),
+    TrackerFailure(String),
+    ScrapeUnsupported,
+    NotTracked,
+    BadAnnounceUrl(String, String) // the URL and why it can't be parsed
 }
 
 impl fmt::Display for TrackerError {
@@ -146,7 +1074,17 @@ impl fmt::Display for TrackerError {
             Self::SerializationError(e) =>
                 write!(f, "{}", e),
             Self::RequestError(e) =>
-                write!(f, "{}", e)
+                write!(f, "{}", e),
+            Self::DecodeError(e) =>
+                write!(f, "Error decompressing tracker response: {}", e),
+            Self::TrackerFailure(reason) =>
+                write!(f, "Tracker refused the request: {}", reason),
+            Self::ScrapeUnsupported =>
+                write!(f, "Tracker doesn't support scraping"),
+            Self::NotTracked =>
+                write!(f, "Tracker doesn't know the torrent"),
+            Self::BadAnnounceUrl(url, e) =>
+                write!(f, "Invalid announce URL {:?}: {}", url, e)
         }
     }
 }
@@ -160,3 +1098,251 @@ impl From<reqwest::Error> for TrackerError {
         Self::RequestError(err)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use crate::connection::Connection;
+    use crate::torrent::tests::torrent;
+
+    const GIB: u64 = 1 << 30;
+
+    fn client() -> Client {
+        Client::new_in_memory(&torrent(10, 16384), DownloadConfig::default()).unwrap()
+    }
+
+    #[test]
+    fn failure_reason_is_surfaced() {
+        match client().parse_tracker_response(b"d14:failure reason22:torrent not registerede") {
+            Err(TrackerError::TrackerFailure(reason)) => assert_eq!(reason, "torrent not registered"),
+            _ => panic!("expected a tracker failure")
+        }
+    }
+
+    #[test]
+    fn pieces_past_4_gib_are_written_at_their_offset() {
+        let torrent = torrent(5 * GIB + 1000, 1 << 20);
+        let options = DownloadOptions {
+            out_dir: std::env::temp_dir().join(format!("bittorrent-client-large-{}", std::process::id())),
+            ..DownloadOptions::default()
+        };
+        // the file stays sparse, only the last piece is written
+        let config = DownloadConfig { preallocate: false, ..DownloadConfig::default() };
+        let client = Client::new(&torrent, &options, config).unwrap();
+        let last = torrent.create_piece_queue().pop_back().unwrap();
+
+        client.complete_piece(&last, vec![7; 1000]).unwrap();
+        client.sync_file().unwrap();
+
+        assert_eq!(fs::metadata(&client.out_path).unwrap().len(), 5 * GIB + 1000);
+        assert_eq!(client.read_piece(last.begin, 1000).unwrap(), vec![7; 1000]);
+
+        fs::remove_dir_all(options.out_dir).unwrap();
+    }
+
+    #[test]
+    fn rate_averages_the_last_samples() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut meter = RateMeter { samples: VecDeque::from(vec![(start, 0)]) };
+
+        meter.record_at(at(10), 1000);
+        assert_eq!(meter.rate_at(at(10)), 100.0);
+
+        // past the window the first sample is dropped, the average starts at 10s
+        meter.record_at(at(30), 5000);
+        assert_eq!(meter.rate_at(at(30)), 200.0);
+
+        // no progress since the last sample, the rate drops
+        assert_eq!(meter.rate_at(at(50)), 100.0);
+    }
+
+    #[test]
+    fn rate_is_zero_without_elapsed_time() {
+        let start = Instant::now();
+        let meter = RateMeter { samples: VecDeque::from(vec![(start, 0)]) };
+
+        assert_eq!(meter.rate_at(start), 0.0);
+        assert_eq!(RateMeter::eta(1000, meter.rate_at(start)), None);
+    }
+
+    #[test]
+    fn eta_is_the_remaining_bytes_at_the_rate() {
+        assert_eq!(RateMeter::eta(500, 100.0), Some(Duration::from_secs(5)));
+        assert_eq!(RateMeter::eta(0, 100.0), Some(Duration::ZERO));
+        assert_eq!(RateMeter::eta(3 * 3600 * 1024, 1024.0), Some(Duration::from_secs(3 * 3600)));
+    }
+
+    #[test]
+    fn ipv6_announce_urls_keep_their_host() {
+        let mut torrent = torrent(10, 16384);
+
+        torrent.announce = "http://[::1]:6969/announce".to_string();
+
+        let url = client().parse_url(&torrent, None, 50).unwrap();
+
+        assert_eq!(url.host_str(), Some("[::1]"));
+        assert_eq!(url.port(), Some(6969));
+        assert_eq!(url.path(), "/announce");
+        assert!(!url.query_pairs().any(|(name, _)| name == "ipv6"));
+
+        let config = DownloadConfig { announce_ipv6: Some("2001:db8::1".parse().unwrap()), ..DownloadConfig::default() };
+        let client = Client::new_in_memory(&torrent, config).unwrap();
+        let url = client.parse_url(&torrent, None, 50).unwrap();
+
+        assert_eq!(url.host_str(), Some("[::1]"));
+        assert_eq!(url.port(), Some(6969));
+        assert!(url.query_pairs().any(|(name, value)| name == "ipv6" && value == "2001:db8::1"));
+    }
+
+    #[test]
+    fn pieces_written_from_several_threads() {
+        const PIECES: u32 = 32;
+        const THREADS: u32 = 8;
+        let piece_length = 16 * 1024;
+        // the last piece is shorter
+        let torrent = torrent(PIECES as u64 * piece_length as u64 - 100, piece_length);
+        let data = (0..torrent.total_length()).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+
+        // written right away and through the write cache
+        for write_cache_size in [0, 5 * piece_length as usize] {
+            let options = DownloadOptions {
+                out_dir: std::env::temp_dir().join(format!("bittorrent-client-threads-{}-{}", write_cache_size, std::process::id())),
+                ..DownloadOptions::default()
+            };
+            let config = DownloadConfig { write_cache_size, ..DownloadConfig::default() };
+            let client = Arc::new(Client::new(&torrent, &options, config).unwrap());
+            let workers = (0..THREADS)
+                .map(|worker| {
+                    let client = client.clone();
+                    let pieces = (worker..PIECES).step_by(THREADS as usize)
+                        .map(|index| torrent.piece_range(index))
+                        .map(|(begin, end)| (begin, data[begin as usize..end as usize].to_vec()))
+                        .collect::<Vec<_>>();
+
+                    thread::spawn(move || {
+                        for (begin, piece) in pieces {
+                            client.write_piece(begin, piece).unwrap();
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            for worker in workers {
+                worker.join().unwrap();
+            }
+
+            client.sync_file().unwrap();
+
+            let written = fs::read(&client.out_path).unwrap();
+
+            for index in 0..PIECES {
+                let (begin, end) = torrent.piece_range(index);
+
+                assert_eq!(&written[begin as usize..end as usize], &data[begin as usize..end as usize], "piece {}", index);
+            }
+
+            assert_eq!(written.len(), data.len());
+
+            fs::remove_dir_all(options.out_dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn nested_output_dirs_are_created() {
+        let base = std::env::temp_dir().join(format!("bittorrent-client-nested-{}", std::process::id()));
+        let options = DownloadOptions { out_dir: base.join("a").join("b").join("c"), ..DownloadOptions::default() };
+        let cwd = std::env::current_dir().unwrap();
+
+        assert!(!base.exists());
+
+        let client = Client::new(&torrent(10, 16384), &options, DownloadConfig::default()).unwrap();
+
+        assert!(options.out_dir.is_dir());
+        assert_eq!(client.out_path, options.out_dir.join("file"));
+        assert!(client.out_path.is_file());
+        assert_eq!(std::env::current_dir().unwrap(), cwd);
+
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn resume_from_an_explicit_output_dir() {
+        let dir = std::env::temp_dir().join(format!("bittorrent-client-resume-{}", std::process::id()));
+        let source = dir.join("source.bin");
+        let data = (0..5 * 16384 - 300).map(|i| (i % 253) as u8).collect::<Vec<u8>>();
+
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&source, &data).unwrap();
+
+        // the piece hashes have to be real for the pieces to be kept
+        let torrent = Torrent::from_bytes(&Torrent::create_from_path(&source, 16384, "http://tracker.example/announce").unwrap()).unwrap();
+        let pieces = torrent.create_piece_queue();
+        let kept = [0, 2, 4];
+
+        for part_suffix in [true, false] {
+            let options = DownloadOptions { out_dir: dir.join(format!("out-{}", part_suffix)), part_suffix, ..DownloadOptions::default() };
+            let config = DownloadConfig { resume: true, ..DownloadConfig::default() };
+            let first = Client::new(&torrent, &options, config.clone()).unwrap();
+
+            for &index in &kept {
+                let piece = &pieces[index];
+                let begin = piece.begin as usize;
+
+                first.complete_piece(piece, data[begin..begin + piece.length as usize].to_vec()).unwrap();
+            }
+
+            first.sync_file().unwrap();
+            drop(first);
+
+            let written = if part_suffix { Client::part_path(&options.out_dir.join("source.bin")) } else { options.out_dir.join("source.bin") };
+
+            assert!(written.is_file());
+
+            let second = Client::new(&torrent, &options, config).unwrap();
+
+            assert_eq!(second.resume().unwrap(), kept.len() as u32);
+
+            for piece in &pieces {
+                assert_eq!(second.torrent.is_completed(piece.index), kept.contains(&(piece.index as usize)));
+            }
+
+            assert_eq!(second.get_piece_queue().len(), pieces.len() - kept.len());
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn idle_checks_forget_closed_connections() {
+        let client = client();
+        let conn = Connection::from_stream(&client, io::Cursor::new(Vec::new()), "127.0.0.1:6881".parse().unwrap());
+
+        client.check_idle_peers();
+        assert_eq!(client.activity.lock().unwrap().len(), 1);
+
+        drop(conn);
+        client.check_idle_peers();
+        assert!(client.activity.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn gzipped_responses_are_decoded() {
+        use std::io::Write;
+        use flate2::{write::GzEncoder, Compression};
+        use reqwest::header::HeaderValue;
+
+        let mut headers = HeaderMap::new();
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+        encoder.write_all(b"d8:intervali1800e5:peers0:e").unwrap();
+
+        let body = Client::decode_body(&headers, encoder.finish().unwrap()).unwrap();
+
+        assert_eq!(body, b"d8:intervali1800e5:peers0:e");
+        assert!(matches!(Client::decode_body(&headers, b"not gzip".to_vec()), Err(TrackerError::DecodeError(_))));
+        assert_eq!(Client::decode_body(&HeaderMap::new(), b"plain".to_vec()).unwrap(), b"plain");
+    }
+}
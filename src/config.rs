@@ -0,0 +1,184 @@
+use std::fmt;
+use std::error::Error;
+use std::net::Ipv6Addr;
+use std::path::PathBuf;
+use std::time::Duration;
+use crate::piece_strategy::PieceOrder;
+
+#[derive(Clone)]
+pub struct DownloadConfig {
+    pub block_size: u32, // size of the blocks requested to peers
+    pub max_concurrent_requests: usize, // requests pipelined to a peer at once
+    pub preallocate: bool, // size the output file up front instead of growing it as pieces arrive
+    pub files: Option<Vec<usize>>, // indexes of the files to download, all of them if None
+    pub announce_ipv6: Option<Ipv6Addr>, // our IPv6 address, sent to the tracker
+    pub write_cache_size: usize, // bytes of finished pieces held in memory before writing them, 0 writes them right away
+    pub peer_retries: u32, // reconnections to a peer after its connection fails
+    pub numwant: u32, // peers asked to the tracker
+    pub piece_order: PieceOrder, // order pieces are downloaded in
+    pub max_piece_failures: u32, // hash failures of a single piece before giving up on the download
+    pub compact: bool, // ask the tracker for the compact peer list
+    pub check_interval: Option<Duration>, // time between re-checks of completed pieces on disk, never if None
+    pub max_memory_size: u64, // biggest torrent `Client::new_in_memory` accepts
+    pub peer_id: Option<Vec<u8>>, // sent to the tracker and peers, random if None
+    pub resume: bool, // keep the pieces already in the output file instead of truncating it
+    pub max_download: Option<u64> // bytes received after which no new piece is started, no limit if None
+}
+
+impl DownloadConfig {
+    // Peers commonly drop connections asking for more than 128 KiB
+    pub const MAX_BLOCK_SIZE: u32 = 131072;
+    pub const MIN_BLOCK_SIZE: u32 = 1024;
+    const DEFAULT_BLOCK_SIZE: u32 = 16384;
+    const DEFAULT_MAX_CONCURRENT_REQUESTS: usize = 5;
+    const DEFAULT_PEER_RETRIES: u32 = 3;
+    const DEFAULT_NUMWANT: u32 = 50;
+    const DEFAULT_MAX_PIECE_FAILURES: u32 = 20;
+    const DEFAULT_MAX_MEMORY_SIZE: u64 = 256 * 1024 * 1024;
+    pub const PEER_ID_LENGTH: usize = 20;
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.block_size < Self::MIN_BLOCK_SIZE || self.block_size > Self::MAX_BLOCK_SIZE {
+            return Err(ConfigError::BlockSize(self.block_size));
+        }
+
+        if self.max_concurrent_requests == 0 {
+            return Err(ConfigError::NoRequests);
+        }
+
+        if self.max_piece_failures == 0 {
+            return Err(ConfigError::NoPieceRetries);
+        }
+
+        if self.check_interval == Some(Duration::ZERO) {
+            return Err(ConfigError::NoCheckInterval);
+        }
+
+        if let Some(peer_id) = &self.peer_id {
+            if peer_id.len() != Self::PEER_ID_LENGTH {
+                return Err(ConfigError::PeerIdLength(peer_id.len()));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// How many peers are dialed and kept, shared by the downloads of every torrent
+#[derive(Clone)]
+pub struct SwarmConfig {
+    pub max_connections: usize, // peers connected or being connected at once, across all torrents
+    pub connect_concurrency: usize, // connection attempts in flight at once, dead peers take seconds to time out
+    pub peer_target: usize // unchoking peers of a torrent after which no more are dialed
+}
+
+impl SwarmConfig {
+    const DEFAULT_MAX_CONNECTIONS: usize = 200;
+    const DEFAULT_CONNECT_CONCURRENCY: usize = 30;
+    const DEFAULT_PEER_TARGET: usize = 50;
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.max_connections == 0 {
+            return Err(ConfigError::NoConnections);
+        }
+
+        if self.connect_concurrency == 0 {
+            return Err(ConfigError::NoConnectConcurrency);
+        }
+
+        if self.peer_target == 0 {
+            return Err(ConfigError::NoPeerTarget);
+        }
+
+        Ok(())
+    }
+}
+
+// Where a torrent is saved. The output directory is joined to the paths instead
+// of changed into, the working directory is process wide.
+#[derive(Clone)]
+pub struct DownloadOptions {
+    pub out_dir: PathBuf, // created if it doesn't exist
+    pub name_override: Option<String>, // saved under this name instead of the torrent name
+    pub part_suffix: bool // files get `.part` appended until the download completes
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        DownloadOptions {
+            out_dir: PathBuf::from("."),
+            name_override: None,
+            part_suffix: false
+        }
+    }
+}
+
+impl Default for SwarmConfig {
+    fn default() -> Self {
+        SwarmConfig {
+            max_connections: Self::DEFAULT_MAX_CONNECTIONS,
+            connect_concurrency: Self::DEFAULT_CONNECT_CONCURRENCY,
+            peer_target: Self::DEFAULT_PEER_TARGET
+        }
+    }
+}
+
+impl Default for DownloadConfig {
+    fn default() -> Self {
+        DownloadConfig {
+            block_size: Self::DEFAULT_BLOCK_SIZE,
+            max_concurrent_requests: Self::DEFAULT_MAX_CONCURRENT_REQUESTS,
+            preallocate: true,
+            files: None,
+            announce_ipv6: None,
+            write_cache_size: 0,
+            peer_retries: Self::DEFAULT_PEER_RETRIES,
+            numwant: Self::DEFAULT_NUMWANT,
+            piece_order: PieceOrder::Fifo,
+            max_piece_failures: Self::DEFAULT_MAX_PIECE_FAILURES,
+            compact: true,
+            check_interval: None,
+            max_memory_size: Self::DEFAULT_MAX_MEMORY_SIZE,
+            peer_id: None,
+            resume: false,
+            max_download: None
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    BlockSize(u32),
+    NoRequests,
+    NoPieceRetries,
+    NoCheckInterval,
+    PeerIdLength(usize),
+    NoConnections,
+    NoConnectConcurrency,
+    NoPeerTarget
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::BlockSize(size) =>
+                write!(f, "Block size {} is out of range, it must be between {} and {} bytes",
+                       size, DownloadConfig::MIN_BLOCK_SIZE, DownloadConfig::MAX_BLOCK_SIZE),
+            Self::NoRequests =>
+                write!(f, "At least one concurrent request is needed"),
+            Self::NoPieceRetries =>
+                write!(f, "Pieces must be allowed to fail at least once"),
+            Self::NoCheckInterval =>
+                write!(f, "The check interval must be at least one second"),
+            Self::PeerIdLength(length) =>
+                write!(f, "The peer_id must be exactly {} bytes but it has {}", DownloadConfig::PEER_ID_LENGTH, length),
+            Self::NoConnections =>
+                write!(f, "At least one peer connection is needed"),
+            Self::NoConnectConcurrency =>
+                write!(f, "At least one connection attempt at a time is needed"),
+            Self::NoPeerTarget =>
+                write!(f, "The peer target must be at least one peer")
+        }
+    }
+}
+impl Error for ConfigError {}
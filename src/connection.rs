@@ -1,16 +1,24 @@
-use std::net::{TcpStream, Ipv4Addr, SocketAddr, IpAddr};
+use std::net::{TcpStream, Shutdown, Ipv4Addr, SocketAddr, SocketAddrV4, IpAddr, AddrParseError};
 use std::error::Error;
 use std::io::{self, Write, Read};
 use std::fmt;
 use std::fmt::Debug;
 use std::string::FromUtf8Error;
-use std::time::Duration;
+use std::str::FromStr;
+use std::convert::TryFrom;
+use std::time::{Duration, Instant};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use core::result;
 use byteorder::{BigEndian, ByteOrder};
+use std::collections::HashMap;
 use serde::{Deserialize, Deserializer, de};
-use serde::de::Visitor;
+use serde_bytes::ByteBuf;
+use serde::de::{Visitor, SeqAccess};
 use crate::message::Message;
-use crate::client::Client;
+use crate::client::{Client, Traffic};
+use crate::bitfield::Bitfield;
+use crate::utils::to_hex;
 
 type Result<T> = result::Result<T, ConnectionError>;
 
@@ -22,11 +30,33 @@ struct Handshake {
     peer_id: Vec<u8>
 }
 
-pub struct Connection {
-    stream: TcpStream,
+// When the peer last sent anything, shared between a connection and the client
+// so a periodic pass of the supervisor can ping idle peers and drop dead ones,
+// whatever the connection's worker is doing
+pub struct PeerActivity {
+    since: Instant, // `last_read` counts from here
+    last_read: AtomicU64, // milliseconds after `since`
+    pinged: AtomicBool, // a keep-alive was sent since `last_read`
+    reaped: AtomicBool, // silent for too long, the connection fails on its next read or write
+    socket: Mutex<Option<TcpStream>>, // clone of the stream, none for in-memory streams
+    write_lock: Mutex<()> // keeps keep-alives from landing in the middle of another message
+}
+
+// Generic over the stream so the protocol logic also runs over in-memory streams
+pub struct Connection<S = TcpStream> {
+    stream: S,
+    shutdown: Arc<AtomicBool>,
+    done: Arc<AtomicBool>, // the torrent finished, waiting for the peer is pointless
+    traffic: Arc<Traffic>, // the client's byte counts, updated on every read and write
+    desynced: bool, // a message was only partially read, the next bytes aren't a message boundary
+    activity: Arc<PeerActivity>, // shared with the client, which pings and reaps idle peers
+    unrequested_blocks: u32, // blocks in a row the peer sent without us asking for them
+    pub addr: SocketAddr,
     pub name: String,
+    pub remote_peer_id: Vec<u8>,
     pub chocked: bool,
-    pub bitfield: Option<Vec<u8>>
+    pub am_interested: bool,
+    pub bitfield: Option<Bitfield>
 }
 
 #[derive(Deserialize, Clone, Copy)]
@@ -35,15 +65,49 @@ pub struct Peer {
     port: u16
 }
 
+// A peer in the dictionary model tracker response, sent when compact mode is off
+#[derive(Deserialize)]
+struct PeerEntry {
+    ip: String,
+    port: u16
+}
+
 #[derive(Deserialize)]
 pub struct TrackerResponse {
-    interval: u32,
-    #[serde(deserialize_with = "Peer::vec_from_bytes")]
+    pub interval: u32,
+    #[serde(rename = "min interval")]
+    pub min_interval: Option<u32>,
+    pub complete: Option<u32>, // seeders
+    pub incomplete: Option<u32>, // leechers
+    #[serde(rename = "tracker id")]
+    pub tracker_id: Option<ByteBuf>, // sent back on the next announces
+    #[serde(deserialize_with = "Peer::vec_from_response")]
     pub peers: Vec<Peer>
 }
 
-impl<'a> Handshake {
-    const PROTOCOL_IDENTIFIER: &'a str = "BitTorrent protocol";
+// Swarm counts of a torrent, answered by the tracker's scrape endpoint
+#[derive(Deserialize)]
+pub struct ScrapeStats {
+    pub complete: u32, // seeders
+    pub incomplete: u32, // leechers
+    #[serde(default)]
+    pub downloaded: u32 // completed downloads ever reported
+}
+
+#[derive(Deserialize)]
+pub struct ScrapeResponse {
+    pub files: HashMap<ByteBuf, ScrapeStats> // keyed by info_hash
+}
+
+// Sent by the tracker instead of a `TrackerResponse` when it rejects the announce
+#[derive(Deserialize)]
+pub struct TrackerFailure {
+    #[serde(rename = "failure reason")]
+    pub reason: String
+}
+
+impl Handshake {
+    const PROTOCOL_IDENTIFIER: &'static str = "BitTorrent protocol";
 
     fn new(info_hash: &Vec<u8>, peer_id: &Vec<u8>) -> Handshake {
         Handshake {
@@ -65,12 +129,29 @@ impl<'a> Handshake {
         result
     }
 
-    fn from_bytes(b: &[u8]) -> result::Result<Handshake, FromUtf8Error> {
-        let pstr_len = 19;
+    // Bytes after the protocol string: reserved, info_hash and peer_id
+    const TAIL_LENGTH: usize = 8 + 20 + 20;
+
+    // `b` is the whole handshake, starting with the protocol string length
+    fn from_bytes(b: &[u8]) -> Result<Handshake> {
+        let pstr_len = match b.first() {
+            Some(&len) => len as usize,
+            None => return Err(ConnectionError::BadHandshake("Empty handshake".to_string()))
+        };
+
+        if b.len() != 1 + pstr_len + Self::TAIL_LENGTH {
+            return Err(ConnectionError::BadHandshake(
+                format!("Expected {} bytes but got {}", 1 + pstr_len + Self::TAIL_LENGTH, b.len())));
+        }
+
         let pstr = String::from_utf8(b[1..pstr_len + 1].to_vec())?;
+
+        if pstr != Self::PROTOCOL_IDENTIFIER {
+            return Err(ConnectionError::BadHandshake(format!("Unknown protocol {:?}", pstr)));
+        }
+
         let info_hash = b[pstr_len + 1 + 8..pstr_len + 1 + 8 + 20].to_vec();
-        let peer_id = &b[pstr_len + 1 + 8 + 20..];
-        let peer_id = peer_id.to_vec();
+        let peer_id = b[pstr_len + 1 + 8 + 20..].to_vec();
 
         Ok(Handshake {
             pstr,
@@ -88,8 +169,33 @@ impl Peer {
         Peer { ip, port }
     }
 
-    fn vec_from_bytes<'de, D: Deserializer<'de>>(d: D) -> result::Result<Vec<Peer>, D::Error> {
-        d.deserialize_byte_buf(PeerVecVisitor)
+    // The peers are either a compact byte string or a list of dictionaries
+    fn vec_from_response<'de, D: Deserializer<'de>>(d: D) -> result::Result<Vec<Peer>, D::Error> {
+        d.deserialize_any(PeerVecVisitor)
+    }
+}
+
+// Parses an `ip:port` address, e.g. a peer given on the command line
+impl FromStr for Peer {
+    type Err = AddrParseError;
+
+    fn from_str(s: &str) -> result::Result<Peer, AddrParseError> {
+        Ok(Peer::from(s.parse::<SocketAddrV4>()?))
+    }
+}
+
+impl From<SocketAddrV4> for Peer {
+    fn from(addr: SocketAddrV4) -> Peer {
+        Peer { ip: *addr.ip(), port: addr.port() }
+    }
+}
+
+impl TrackerResponse {
+    // Time to wait before the next announce, never below the tracker's `min interval`
+    pub fn announce_interval(&self) -> Duration {
+        let interval = self.interval.max(self.min_interval.unwrap_or(0));
+
+        Duration::from_secs(interval as u64)
     }
 }
 
@@ -103,110 +209,373 @@ impl <'de> Visitor<'de> for PeerVecVisitor {
     type Value = Vec<Peer>;
 
     fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        formatter.write_str("byte array")
+        formatter.write_str("byte array or list of peers")
     }
 
     fn visit_bytes<E: de::Error>(self, v: &[u8]) -> result::Result<Self::Value, E> {
-        Ok(v.chunks(6).map(Peer::from_bytes).collect())
+        // a truncated entry means the whole string can't be trusted
+        if !v.len().is_multiple_of(6) {
+            return Err(E::invalid_length(v.len(), &"a multiple of 6 bytes"));
+        }
+
+        Ok(v.chunks_exact(6).map(Peer::from_bytes).collect())
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> result::Result<Self::Value, A::Error> {
+        let mut peers = Vec::new();
+
+        while let Some(entry) = seq.next_element::<PeerEntry>()? {
+            // hostnames and IPv6 addresses can't be connected to yet
+            if let Ok(ip) = entry.ip.parse::<Ipv4Addr>() {
+                peers.push(Peer { ip, port: entry.port });
+            }
+        }
+
+        Ok(peers)
     }
 }
 
 impl Connection {
+    // Reads wake up this often to check the shutdown flag
+    const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
     pub fn new(client: &Client, peer: Peer) -> Result<Connection> {
+        Self::connect(client, peer, None)
+    }
+
+    // Like `new`, but drops the peer if it doesn't identify itself with `peer_id`
+    pub fn new_expecting(client: &Client, peer: Peer, peer_id: &[u8]) -> Result<Connection> {
+        Self::connect(client, peer, Some(peer_id))
+    }
+
+    fn connect(client: &Client, peer: Peer, expected_peer_id: Option<&[u8]>) -> Result<Connection> {
         let addr = SocketAddr::from(peer);
         let stream = TcpStream::connect_timeout(&addr, Duration::from_secs(3))?;
 
         stream.set_write_timeout(Some(Duration::from_secs(5)))?;
-        stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+        stream.set_read_timeout(Some(Self::POLL_INTERVAL))?;
 
-        let mut conn = Connection {
-            name: peer.ip.to_string(),
-            stream,
-            chocked: true,
-            bitfield: None
-        };
+        let mut conn = Connection::from_stream(client, stream.try_clone()?, addr);
 
-        conn.complete_handshake(client)?;
+        *conn.activity.socket.lock().unwrap() = Some(stream);
+        conn.complete_handshake(client, expected_peer_id)?;
 
         Ok(conn)
     }
 
+    // Tells the peer we are leaving instead of just dropping the socket. Best effort:
+    // errors are ignored and nothing blocks, a dead peer can't slow the shutdown down.
+    pub fn close(mut self) {
+        let _ = self.stream.set_nonblocking(true);
+
+        if self.am_interested {
+            let _ = self.send(Message::NotInterested);
+        }
+
+        let _ = self.stream.shutdown(Shutdown::Write);
+    }
+}
+
+impl PeerActivity {
+    // A peer silent for longer than this is considered dead
+    const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+    // A peer silent for this long is sent a keep-alive, a half-open connection only fails on write
+    const KEEP_ALIVE_AFTER: Duration = Duration::from_secs(15);
+
+    fn new() -> PeerActivity {
+        PeerActivity {
+            since: Instant::now(),
+            last_read: AtomicU64::new(0),
+            pinged: AtomicBool::new(false),
+            reaped: AtomicBool::new(false),
+            socket: Mutex::new(None),
+            write_lock: Mutex::new(())
+        }
+    }
+
+    fn touch(&self) {
+        self.last_read.store(self.since.elapsed().as_millis() as u64, Ordering::Relaxed);
+        self.pinged.store(false, Ordering::Relaxed);
+    }
+
+    pub fn last_read(&self) -> Instant {
+        self.since + Duration::from_millis(self.last_read.load(Ordering::Relaxed))
+    }
+
+    pub fn is_reaped(&self) -> bool {
+        self.reaped.load(Ordering::Relaxed)
+    }
+
+    // Sends a keep-alive to a peer silent for KEEP_ALIVE_AFTER and drops one silent for
+    // IDLE_TIMEOUT. Shutting the socket down also wakes up a worker blocked reading it.
+    pub fn check(&self) {
+        let idle = self.last_read().elapsed();
+
+        if self.is_reaped() {
+            return;
+        }
+
+        if idle >= Self::IDLE_TIMEOUT {
+            self.reaped.store(true, Ordering::Relaxed);
+
+            if let Some(socket) = self.socket.lock().unwrap().as_ref() {
+                let _ = socket.shutdown(Shutdown::Both);
+            }
+        } else if idle >= Self::KEEP_ALIVE_AFTER && !self.pinged.swap(true, Ordering::Relaxed) {
+            // skipped while the worker is writing, that write keeps the connection busy anyway
+            if let Ok(_guard) = self.write_lock.try_lock() {
+                if let Some(mut socket) = self.socket.lock().unwrap().as_ref() {
+                    let _ = socket.write_all(&Message::KeepAlive.serialize());
+                }
+            }
+        }
+    }
+
+    fn idle_error() -> io::Error {
+        io::Error::new(io::ErrorKind::TimedOut, format!("Peer silent for {} seconds", Self::IDLE_TIMEOUT.as_secs()))
+    }
+}
+
+impl<S: Read + Write> Connection<S> {
+    // Bigger than any block or bitfield we expect, a longer length means a broken stream
+    const MAX_MESSAGE_LENGTH: u32 = 1 << 21;
+
+    // Wraps a stream to `addr` before the handshake and registers it with the client's
+    // idle checks. Its reads should time out regularly, or the shutdown flag and
+    // reaped peers are only noticed when data arrives.
+    pub fn from_stream(client: &Client, stream: S, addr: SocketAddr) -> Connection<S> {
+        let activity = Arc::new(PeerActivity::new());
+
+        client.watch_activity(&activity);
+
+        Connection {
+            name: addr.ip().to_string(),
+            remote_peer_id: Vec::new(),
+            addr,
+            stream,
+            shutdown: client.get_shutdown_flag(),
+            done: client.get_done_flag(),
+            traffic: client.get_traffic(),
+            desynced: false,
+            activity,
+            unrequested_blocks: 0,
+            chocked: true,
+            am_interested: false,
+            bitfield: None
+        }
+    }
+
     pub fn send(&mut self, message: Message) -> io::Result<()> {
-        self.stream.write_all(&message.serialize())?;
+        self.write_all(&message.serialize())
+    }
+
+    fn write_all(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let _guard = self.activity.write_lock.lock().unwrap();
+
+        if self.activity.is_reaped() {
+            return Err(PeerActivity::idle_error());
+        }
+
+        self.stream.write_all(bytes)?;
+        self.traffic.add_sent(bytes.len() as u64);
 
         Ok(())
     }
 
     pub fn read(&mut self) -> io::Result<Message> {
         let mut buf = [0; 4];
-        let mut stream = &self.stream;
-        let mut msg = Vec::new();
 
-        stream.read_exact(&mut buf)?;
+        if self.desynced {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Stream is out of sync"));
+        }
+
+        self.read_exact(&mut buf, true)?;
 
         let msg_len = BigEndian::read_u32(&buf);
 
-        stream.take(msg_len as u64).read_to_end(&mut msg)?;
+        if msg_len > Self::MAX_MESSAGE_LENGTH {
+            self.desynced = true;
 
-        if msg_len > 0 {
-            Ok(Message::new(msg[0], &msg[1..]))
-        } else {
-            Ok(Message::KeepAlive)
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("Message length {} is too big", msg_len)));
         }
+
+        let mut msg = vec![0; msg_len as usize];
+
+        // a message that already started arriving is read to the end even when shutting down
+        self.read_exact(&mut msg, false)?;
+
+        Message::try_from(&msg[..]).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
     }
 
-    pub fn has_piece(&self, index: &u32) -> bool {
-        let bitfield = self.bitfield.to_owned().expect("Bitfield not found");
-        let byte_index = index / 8;
-        let offset = index % 8;
+    // Counts a block we didn't ask for, returns how many arrived in a row
+    pub fn add_unrequested_block(&mut self) -> u32 {
+        self.unrequested_blocks += 1;
+        self.unrequested_blocks
+    }
+
+    pub fn reset_unrequested_blocks(&mut self) {
+        self.unrequested_blocks = 0;
+    }
+
+    // When the peer last sent us anything, the clock the keep-alives and the idle timeout use
+    pub fn last_activity(&self) -> Instant {
+        self.activity.last_read()
+    }
+
+    // Stores the peer's bitfield if it has exactly one bit per piece, with the padding bits of the last byte cleared
+    pub fn set_bitfield(&mut self, bitfield: Vec<u8>, piece_count: u32) -> io::Result<()> {
+        self.bitfield = Some(Bitfield::from_bytes(bitfield, piece_count)?);
+
+        Ok(())
+    }
+
+    // Indexes of the pieces the peer has, empty before its bitfield arrives
+    pub fn pieces(&self) -> Vec<u32> {
+        self.bitfield.as_ref().map_or_else(Vec::new, Bitfield::pieces)
+    }
 
-        bitfield[byte_index as usize] & (1 << (7 - offset)) != 0
+    // Pieces the peer advertises, 0 before its bitfield arrives
+    pub fn piece_count(&self) -> u32 {
+        self.bitfield.as_ref().map_or(0, Bitfield::count_ones)
     }
 
-    pub fn set_piece(&mut self, index: &u32) {
-        let bitfield = self.bitfield.as_mut().expect("Bitfield not found");
-        let byte_index = index / 8;
-        let offset = index % 8;
+    // Whether the peer has every piece of the torrent
+    pub fn is_seed(&self) -> bool {
+        self.bitfield.as_ref().is_some_and(Bitfield::is_complete)
+    }
+
+    // False before the peer's bitfield arrives
+    pub fn has_piece(&self, index: &u32) -> bool {
+        self.bitfield.as_ref().is_some_and(|bitfield| bitfield.has(*index))
+    }
+
+    // Whether the peer has any of the `needed` pieces, false until its bitfield arrives
+    pub fn has_any_needed(&self, needed: &[u32]) -> bool {
+        needed.iter().any(|index| self.has_piece(index))
+    }
 
-        bitfield[byte_index as usize] |= 1 << (7 - offset);
+    // Ignored for indexes out of range. A peer starting with no pieces may skip the
+    // bitfield (BEP 3), its first Have starts an empty one of `piece_count` pieces.
+    pub fn set_piece(&mut self, index: &u32, piece_count: u32) {
+        self.bitfield
+            .get_or_insert_with(|| Bitfield::new_empty(piece_count))
+            .set(*index);
     }
 
     fn send_handshake(&mut self, client: &Client) -> io::Result<Handshake> {
         let hs = Handshake::new(&client.torrent.info_hash, &client.id);
 
-        self.stream.write_all(&hs.as_bytes().as_slice())?;
+        self.write_all(hs.as_bytes().as_slice())?;
 
         Ok(hs)
     }
 
     fn receive_handshake(&mut self) -> Result<Handshake> {
-        let mut buf = [0; 68];
+        let mut pstr_len = [0; 1];
+
+        // peers that only accept encrypted connections (MSE) drop a plaintext handshake right away
+        if let Err(e) = self.read_exact(&mut pstr_len, true) {
+            return match e.kind() {
+                io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted =>
+                    Err(ConnectionError::EncryptionRequired),
+                _ => Err(ConnectionError::from(e))
+            };
+        }
+
+        // read as much as the peer says its protocol string takes, checked by `from_bytes`
+        let mut buf = vec![0; 1 + pstr_len[0] as usize + Handshake::TAIL_LENGTH];
 
-        self.stream.read_exact(&mut buf)?;
+        buf[0] = pstr_len[0];
+        self.read_exact(&mut buf[1..], false)?;
 
         let res_hs = Handshake::from_bytes(&buf)?;
 
         Ok(res_hs)
     }
 
-    fn complete_handshake(&mut self, client: &Client) -> Result<()> {
+    // Like `Read::read_exact`, but keeps waiting through the short read timeouts
+    // until the client reaps the peer for being idle too long or, if `interruptible`, a shutdown
+    // is requested or the torrent finishes before any byte arrived. Failing halfway leaves the connection desynced.
+    fn read_exact(&mut self, buf: &mut [u8], interruptible: bool) -> io::Result<()> {
+        let result = self.try_read_exact(buf, interruptible);
+
+        if let Err((_, filled)) = &result {
+            self.desynced |= *filled > 0 || !interruptible;
+        }
+
+        result.map_err(|(e, _)| e)
+    }
+
+    // Returns the error along with how many bytes were read before it
+    fn try_read_exact(&mut self, buf: &mut [u8], interruptible: bool) -> result::Result<(), (io::Error, usize)> {
+        let mut filled = 0;
+
+        while filled < buf.len() {
+            if interruptible && filled == 0 {
+                if self.shutdown.load(Ordering::Relaxed) {
+                    return Err((io::Error::new(io::ErrorKind::Interrupted, "Shutting down"), filled));
+                }
+
+                if self.done.load(Ordering::Relaxed) {
+                    return Err((io::Error::new(io::ErrorKind::Interrupted, "Download finished"), filled));
+                }
+            }
+
+            // a reaped peer's socket is shut down, its reads end right away
+            if self.activity.is_reaped() {
+                return Err((PeerActivity::idle_error(), filled));
+            }
+
+            match self.stream.read(&mut buf[filled..]) {
+                Ok(0) if self.activity.is_reaped() => return Err((PeerActivity::idle_error(), filled)),
+                Ok(0) => return Err((io::Error::from(io::ErrorKind::UnexpectedEof), filled)),
+                Ok(n) => {
+                    filled += n;
+                    self.traffic.add_received(n as u64);
+                    self.activity.touch();
+                },
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {},
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err((e, filled))
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn complete_handshake(&mut self, client: &Client, expected_peer_id: Option<&[u8]>) -> Result<()> {
         let hs = self.send_handshake(client)?;
         let res_hs = self.receive_handshake()?;
 
-        if hs.info_hash.eq(&res_hs.info_hash) {
-            Ok(())
-        } else {
-            Err(ConnectionError::from(WrongHash(hs.info_hash, res_hs.info_hash)))
+        if !hs.info_hash.eq(&res_hs.info_hash) {
+            return Err(ConnectionError::from(WrongHash(hs.info_hash, res_hs.info_hash)));
+        }
+
+        // the tracker can hand our own address back, we recognize ourselves by peer_id
+        if hs.peer_id.eq(&res_hs.peer_id) {
+            return Err(ConnectionError::SelfConnection);
         }
+
+        if let Some(expected_peer_id) = expected_peer_id {
+            if expected_peer_id != res_hs.peer_id.as_slice() {
+                return Err(ConnectionError::UnexpectedPeerId(res_hs.peer_id));
+            }
+        }
+
+        self.remote_peer_id = res_hs.peer_id;
+
+        Ok(())
     }
 }
 
+// The peer is in another swarm, the info_hash we requested and the one it's serving
 #[derive(Debug)]
 pub struct WrongHash(Vec<u8>, Vec<u8>);
 
 impl fmt::Display for WrongHash {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Incorrect info_hash, Expected info_hash: {:?} but got {:?}", self.0, self.1)
+        write!(f, "Peer is serving another torrent, we requested info_hash {} but peer is serving {}", to_hex(&self.0), to_hex(&self.1))
     }
 }
 
@@ -216,7 +585,11 @@ impl Error for WrongHash {}
 pub enum ConnectionError {
     WrongHash(WrongHash),
     IOError(io::Error),
-    Utf8Error(FromUtf8Error)
+    Utf8Error(FromUtf8Error),
+    SelfConnection,
+    UnexpectedPeerId(Vec<u8>),
+    BadHandshake(String),
+    EncryptionRequired
 }
 
 impl fmt::Display for ConnectionError {
@@ -227,7 +600,15 @@ impl fmt::Display for ConnectionError {
             Self::Utf8Error(e) =>
                 write!(f, "{}", e),
             Self::IOError(e) =>
-                write!(f, "{}", e)
+                write!(f, "{}", e),
+            Self::SelfConnection =>
+                write!(f, "Peer is ourselves"),
+            Self::UnexpectedPeerId(peer_id) =>
+                write!(f, "Peer identified itself with an unexpected peer_id: {:?}", peer_id),
+            Self::BadHandshake(reason) =>
+                write!(f, "Invalid handshake: {}", reason),
+            Self::EncryptionRequired =>
+                write!(f, "Peer closed the connection on our handshake, it probably requires encryption")
         }
     }
 }
@@ -246,3 +627,180 @@ impl From<FromUtf8Error> for ConnectionError {
         Self::Utf8Error(err)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DownloadConfig;
+    use crate::torrent::tests::torrent;
+
+    // Hands out the data a few bytes at a time, like a slow TCP stream
+    struct ChunkedStream {
+        data: Vec<u8>,
+        read: usize,
+        chunk: usize
+    }
+
+    impl Read for ChunkedStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = self.chunk.min(buf.len()).min(self.data.len() - self.read);
+
+            buf[..len].copy_from_slice(&self.data[self.read..self.read + len]);
+            self.read += len;
+
+            Ok(len)
+        }
+    }
+
+    impl Write for ChunkedStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn connection(data: Vec<u8>, chunk: usize) -> Connection<ChunkedStream> {
+        let client = Client::new_in_memory(&torrent(10, 16384), DownloadConfig::default()).unwrap();
+
+        Connection::from_stream(&client, ChunkedStream { data, read: 0, chunk }, "127.0.0.1:6881".parse().unwrap())
+    }
+
+    // Activity of a peer last heard from `idle` ago
+    fn idle_activity(idle: Duration) -> PeerActivity {
+        PeerActivity { since: Instant::now() - idle, ..PeerActivity::new() }
+    }
+
+    #[test]
+    fn idle_peers_are_pinged() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let activity = idle_activity(Duration::from_secs(16));
+        let mut peer = {
+            *activity.socket.lock().unwrap() = Some(TcpStream::connect(listener.local_addr().unwrap()).unwrap());
+            listener.accept().unwrap().0
+        };
+        let mut keep_alive = [1; 4];
+
+        activity.check();
+        activity.check();
+        peer.read_exact(&mut keep_alive).unwrap();
+
+        assert_eq!(keep_alive, [0; 4]);
+        assert!(!activity.is_reaped());
+
+        // only one keep-alive until the peer sends something
+        drop(activity);
+        assert_eq!(peer.read(&mut keep_alive).unwrap(), 0);
+    }
+
+    #[test]
+    fn dead_peers_are_reaped() {
+        let mut conn = connection(Message::Have(3).serialize(), 8);
+
+        conn.activity = Arc::new(idle_activity(Duration::from_secs(31)));
+        conn.activity.check();
+
+        assert!(conn.activity.is_reaped());
+        assert_eq!(conn.read().unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert_eq!(conn.send(Message::Interested).unwrap_err().kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn have_without_a_bitfield_starts_an_empty_one() {
+        let mut conn = connection(Vec::new(), 1);
+
+        assert!(!conn.has_any_needed(&[0, 1, 2]));
+
+        conn.set_piece(&2, 10);
+
+        assert!(conn.has_piece(&2));
+        assert!(!conn.has_piece(&1));
+        assert!(conn.has_any_needed(&[0, 1, 2]));
+        assert_eq!(conn.pieces(), vec![2]);
+
+        // out of range indexes are ignored
+        conn.set_piece(&10, 10);
+        assert_eq!(conn.pieces(), vec![2]);
+    }
+
+    #[test]
+    fn messages_split_across_reads_are_reassembled() {
+        let messages = vec![Message::Have(3), Message::KeepAlive, Message::Piece(1, 16384, vec![9; 100]), Message::Unchoke];
+        let expected = messages.iter().map(|message| format!("{:?}", message)).collect::<Vec<_>>();
+        let data = messages.into_iter().flat_map(Message::serialize).collect();
+        let mut conn = connection(data, 3);
+
+        for message in expected {
+            assert_eq!(format!("{:?}", conn.read().unwrap()), message);
+        }
+
+        assert_eq!(conn.read().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn a_message_cut_short_desyncs_the_stream() {
+        let mut data = Message::Have(3).serialize();
+
+        data.pop();
+
+        let mut conn = connection(data, 2);
+
+        assert!(conn.read().is_err());
+        assert_eq!(conn.read().unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    fn handshake_bytes() -> Vec<u8> {
+        Handshake::new(&vec![1; 20], &vec![2; 20]).as_bytes()
+    }
+
+    #[test]
+    fn handshake_round_trips() {
+        let handshake = Handshake::from_bytes(&handshake_bytes()).unwrap();
+
+        assert_eq!(handshake.info_hash, vec![1; 20]);
+        assert_eq!(handshake.peer_id, vec![2; 20]);
+    }
+
+    #[test]
+    fn malformed_handshakes_are_rejected() {
+        let mut short = handshake_bytes();
+        let mut long_pstr = handshake_bytes();
+        let mut other_protocol = handshake_bytes();
+
+        short.truncate(60);
+        long_pstr[0] = 30;
+        other_protocol[1] = b'b';
+
+        for bytes in [vec![], short, long_pstr, other_protocol] {
+            assert!(matches!(Handshake::from_bytes(&bytes), Err(ConnectionError::BadHandshake(_))));
+        }
+    }
+
+    #[test]
+    fn compact_peers_are_parsed() {
+        let response = b"d8:intervali1800e5:peers12:\x7f\x00\x00\x01\x1a\xe1\x0a\x00\x00\x02\x1a\xe2e";
+        let response = serde_bencode::from_bytes::<TrackerResponse>(response).unwrap();
+
+        assert_eq!(response.peers.len(), 2);
+        assert_eq!(SocketAddr::from(response.peers[0]), "127.0.0.1:6881".parse().unwrap());
+        assert_eq!(SocketAddr::from(response.peers[1]), "10.0.0.2:6882".parse().unwrap());
+    }
+
+    #[test]
+    fn truncated_compact_peers_are_rejected() {
+        let response = b"d8:intervali1800e5:peers8:\x7f\x00\x00\x01\x1a\xe1\x0a\x00e";
+
+        assert!(serde_bencode::from_bytes::<TrackerResponse>(response).is_err());
+    }
+
+    #[test]
+    fn dictionary_peers_skip_hostnames() {
+        let response = b"d8:intervali1800e5:peersld2:ip9:127.0.0.14:porti6881eed2:ip11:example.com4:porti6881eeee";
+        let response = serde_bencode::from_bytes::<TrackerResponse>(response).unwrap();
+
+        assert_eq!(response.peers.len(), 1);
+        assert_eq!(SocketAddr::from(response.peers[0]), "127.0.0.1:6881".parse().unwrap());
+    }
+}
@@ -1,14 +1,12 @@
 use std::sync::Arc;
-use std::{thread, io, fmt};
-use std::fs::File;
-use std::io::{Seek, SeekFrom, Write};
-use std::thread::JoinHandle;
-use sha1::{Sha1, Digest};
+use std::{io, fmt};
 use crate::message::Message;
 use crate::connection::Connection;
 use crate::torrent::{Piece, Block, IntegrityError};
-use crate::println_thread;
-use crate::client::Client;
+use log::{debug, info, error};
+use crate::utils::format_eta;
+use crate::client::{Client, Counter};
+use crate::config::DownloadConfig;
 
 pub struct DownloaderWorker {
     conn: Connection,
@@ -17,10 +15,10 @@ pub struct DownloaderWorker {
 
 struct PieceState {
     index: u32,
-    begin: u32,
     requested_blocks: Vec<Block>,
-    blocks_done: u8,
+    rejected_blocks: u8,
     block_queue: Vec<Block>,
+    max_concurrent_requests: usize,
     buf: Vec<u8>
 }
 
@@ -32,47 +30,86 @@ impl DownloaderWorker {
         }
     }
 
-    pub fn start(mut self) -> JoinHandle<()> {
-        thread::Builder::new()
-            .name(format!("{}", &self.conn.name))
-            .spawn(move || {
-                while self.conn.chocked {
-                    match self.conn.read() {
-                        Ok(msg) => self.interpret_message(msg).unwrap(),
-                        Err(_) => break
-                    }
-                }
-            }).expect("Error starting worker.")
+    // Talks to the peer until the download ends or the connection fails
+    pub fn run(mut self) -> io::Result<()> {
+        let result = self.serve();
+
+        // the peer's pieces are no longer available from this connection
+        for index in self.conn.pieces() {
+            self.client.torrent.strategy.update_availability(index, false);
+        }
+
+        set_choked(&mut self.conn, &self.client, true);
+
+        self.conn.close();
+
+        result
     }
 
-    fn download(&mut self) {
-        while !self.client.is_done() {
-            match self.get_piece_from_queue() {
-                Some(work_piece) => {
-                    if !self.conn.has_piece(&work_piece.index) {
-                        self.push_piece_to_queue(work_piece);
+    fn serve(&mut self) -> io::Result<()> {
+        while self.conn.chocked && !self.client.is_done() {
+            let message = match self.conn.read() {
+                Ok(message) => message,
+                // woken up because another worker downloaded the last piece
+                Err(e) if e.kind() == io::ErrorKind::Interrupted && self.client.is_done() => break,
+                Err(e) => return Err(e)
+            };
 
-                        continue;
-                    }
+            self.interpret_message(message)?;
+        }
 
-                    match self.try_download_piece(&work_piece) {
-                        Ok(piece) => {
-                            let mut done_pieces = self.client.get_done_pieces();
-                            let mut file = self.client.get_file();
+        Ok(())
+    }
 
-                            piece.copy_to_file(&mut *file).unwrap();
-                            *done_pieces += 1;
+    fn download(&mut self) -> io::Result<()> {
+        // pieces this peer kept rejecting, left for other peers
+        let mut rejected = Vec::new();
 
-                            println!("Piece {} finished. Pieces done: {} / {} from {} peers",
-                                     &work_piece.index,
-                                     &done_pieces,
-                                     &self.client.torrent.total_pieces,
-                                     Arc::strong_count(&self.client) - 1);
+        while !self.client.is_done() && !self.client.is_shutting_down() && !self.client.is_over_quota() {
+            match self.get_piece_from_queue(&rejected) {
+                Some(mut work_piece) => {
+                    match self.try_download_piece(&work_piece) {
+                        Ok(piece) => {
+                            let done_pieces = match self.client.complete_piece(&work_piece, piece.buf) {
+                                Ok(done_pieces) => done_pieces,
+                                Err(e) => {
+                                    error!("Error writing piece {}: {}", work_piece.index, e);
+                                    self.push_piece_to_queue(work_piece);
+
+                                    break;
+                                }
+                            };
+
+                            info!("Piece {} finished. Pieces done: {} / {} from {} peers, {:.2} MiB/s, ETA {}",
+                                  &work_piece.index,
+                                  &done_pieces,
+                                  &self.client.torrent.total_pieces,
+                                  self.client.peer_count(),
+                                  self.client.overall_rate() / (1024.0 * 1024.0),
+                                  format_eta(self.client.eta()));
                         }
-                        Err(_) => {
-                            self.push_piece_to_queue(work_piece/*.to_owned()*/);
+                        Err(DownloadPieceError::IOError(e)) => {
+                            self.push_piece_to_queue(work_piece);
+
+                            return Err(e);
+                        },
+                        Err(DownloadPieceError::Rejected) => {
+                            debug!("Peer rejected too many requests for piece {}, leaving it for another peer", work_piece.index);
+                            rejected.push(work_piece.index);
+                            self.push_piece_to_queue(work_piece);
+                        },
+                        Err(DownloadPieceError::WrongHash(_)) => {
+                            let failures = self.client.torrent.add_failure(work_piece.index);
+
+                            // every peer sending bad data for it, the piece can't be downloaded
+                            if failures >= self.client.config.max_piece_failures {
+                                self.client.torrent.set_bad_piece(work_piece.index);
+                                self.client.shutdown();
+                            }
+
+                            work_piece.add_failed_peer(&self.conn.remote_peer_id);
+                            self.push_piece_to_queue(work_piece);
 
-                            // FIXME: break only on specific errors
                             break;
                         }
                     }
@@ -80,36 +117,69 @@ impl DownloaderWorker {
                 None => break
             }
         }
+
+        Ok(())
     }
 
-    fn try_download_piece<'a>(&'a mut self, piece: &'a Piece) -> Result<PieceState, DownloadPieceError> {
-        let mut state = PieceState::new(piece);
+    fn try_download_piece(&mut self, piece: &Piece) -> Result<PieceState, DownloadPieceError> {
+        let mut state = PieceState::new(piece, &self.client.config);
 
         while !state.block_queue.is_empty() || !state.requested_blocks.is_empty() {
             if !self.conn.chocked {
                 while state.can_send_request() && !state.block_queue.is_empty() {
                     match state.block_queue.pop() {
-                        Some(b) => state.send_request(b, &mut self.conn)?,
-                        None => println!("Empty block queue")
+                        Some(b) => state.send_request(b, &mut self.conn, &self.client)?,
+                        None => debug!("Empty block queue")
                     }
                 }
             }
 
-            match state.read_message(&mut self.conn)? {
+            let message = match state.read_message(&mut self.conn, &self.client) {
+                Ok(message) => message,
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut {
+                        self.client.count(Counter::BlockRejected, state.requested_blocks.len() as u64);
+                    }
+
+                    return Err(DownloadPieceError::from(e));
+                }
+            };
+
+            if state.rejected_blocks > PieceState::MAX_REJECTED_BLOCKS {
+                return Err(DownloadPieceError::Rejected);
+            }
+
+            match message {
                 Some(block) => state.store_block_in_buffer(block),
                 None => continue
             }
         }
 
-        piece.check_integrity(Sha1::digest(&state.buf).to_vec())?;
+        if let Err(e) = piece.verify(&state.buf) {
+            self.client.count(Counter::PieceFailed, 1);
+
+            return Err(DownloadPieceError::from(e));
+        }
 
         Ok(state)
     }
 
-    fn get_piece_from_queue(&self) -> Option<Piece> {
+    // Takes the piece the strategy picks among the queued ones the peer has, but not
+    // the `skipped` ones. Pieces this peer already sent corrupt are only handed back
+    // when it has nothing else.
+    fn get_piece_from_queue(&self, skipped: &[u32]) -> Option<Piece> {
         let mut piece_queue = self.client.get_piece_queue();
-
-        piece_queue.pop_front()
+        let (failed, available): (Vec<&Piece>, Vec<&Piece>) = piece_queue.iter()
+            .filter(|piece| self.conn.has_piece(&piece.index) && !skipped.contains(&piece.index))
+            .partition(|piece| piece.has_failed(&self.conn.remote_peer_id));
+        let candidates = if available.is_empty() { failed } else { available };
+        let available = candidates.iter()
+            .map(|piece| piece.index)
+            .collect::<Vec<u32>>();
+        let index = self.client.torrent.strategy.select(&available, &self.conn)?;
+        let position = piece_queue.iter().position(|piece| piece.index == index)?;
+
+        piece_queue.remove(position)
     }
 
     fn push_piece_to_queue(&self, piece: Piece) {
@@ -118,20 +188,58 @@ impl DownloaderWorker {
         pieces_queue.push_back(piece);
     }
 
+    fn see_piece(&mut self, index: u32) {
+        if !self.conn.has_piece(&index) {
+            self.conn.set_piece(&index, self.client.torrent.piece_count());
+            self.client.torrent.strategy.update_availability(index, true);
+        }
+    }
+
+    // Tells the peer whether it has any piece we still need, only when that changes
+    fn update_interest(&mut self) -> io::Result<()> {
+        let interested = self.conn.has_any_needed(&self.client.torrent.missing_pieces());
+
+        if interested != self.conn.am_interested {
+            if interested {
+                self.conn.send(Message::Interested)?;
+            } else {
+                self.conn.send(Message::NotInterested)?;
+            }
+
+            self.conn.am_interested = interested;
+        }
+
+        Ok(())
+    }
+
     fn interpret_message(&mut self, message: Message) -> io::Result<()> {
         match message {
             Message::Bitfield(bitfield) => {
-                self.conn.bitfield = Some(bitfield);
+                self.conn.set_bitfield(bitfield, self.client.torrent.piece_count())?;
+
+                if self.conn.is_seed() {
+                    debug!("Peer is a seed");
+                } else {
+                    debug!("Peer has {} / {} pieces", self.conn.piece_count(), self.client.torrent.piece_count());
+                }
+
+                for index in self.conn.pieces() {
+                    self.client.torrent.strategy.update_availability(index, true);
+                }
 
                 self.conn.send(Message::Unchoke)?;
-                self.conn.send(Message::Interested)?;
+                self.update_interest()?;
             },
             Message::Unchoke => {
-                self.conn.chocked = false;
+                set_choked(&mut self.conn, &self.client, false);
 
-                &self.download();
+                self.download()?;
+                self.update_interest()?;
+            },
+            Message::Have(index) => {
+                self.see_piece(index);
+                self.update_interest()?;
             },
-            Message::Have(index) => self.conn.set_piece(&index),
             _ => {}
         }
 
@@ -140,66 +248,98 @@ impl DownloaderWorker {
 }
 
 impl PieceState {
-    const MAX_CONCURRENT_REQUESTS: usize = 5;
+    // after this many rejects the piece is left for another peer, the connection is kept
+    const MAX_REJECTED_BLOCKS: u8 = 3;
+    // after this many blocks we didn't ask for in a row, besides a pipeline's worth, the peer is dropped
+    const MAX_UNREQUESTED_BLOCKS: usize = 10;
 
-    fn new(piece: &Piece) -> PieceState {
+    fn new(piece: &Piece, config: &DownloadConfig) -> PieceState {
         PieceState {
             index: piece.index,
-            begin: piece.begin,
             requested_blocks: Vec::new(),
             buf: vec![0; piece.length as usize],
-            block_queue: piece.create_block_queue(),
-            blocks_done: 0
+            block_queue: piece.create_block_queue(config.block_size),
+            max_concurrent_requests: config.max_concurrent_requests,
+            rejected_blocks: 0
         }
     }
 
-    fn send_request(&mut self, block: Block, conn: &mut Connection) -> io::Result<()> {
-        conn.send(Message::Request(self.index, block.begin, block.length))?;
+    fn send_request(&mut self, block: Block, conn: &mut Connection, client: &Client) -> io::Result<()> {
+        conn.send(Message::Request(block.index, block.begin, block.length))?;
         self.requested_blocks.push(block);
+        client.count(Counter::BlockRequested, 1);
 
         Ok(())
     }
 
-    fn read_message(&mut self, conn: &mut Connection) -> io::Result<Option<Block>> {
+    fn read_message(&mut self, conn: &mut Connection, client: &Client) -> io::Result<Option<Block>> {
         match conn.read()? {
             Message::Piece(index, begin, block_data) => {
-                if index != self.index {
-                    println_thread!("Expected piece ID {} but got {}", &self.index, &index);
-
-                    return Ok(None);
-                }
-
                 let block_index = self.requested_blocks.iter()
-                    .position(|b| b.begin == begin);
+                    .position(|b| b.index == index && b.begin == begin);
 
                 match block_index {
                     Some(block_index) => {
                         let mut block = self.requested_blocks.remove(block_index);
 
-                        self.blocks_done += 1;
+                        if block_data.len() != block.length as usize {
+                            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                      format!("Peer sent {} bytes for block {} of piece {} but {} were requested",
+                                                              block_data.len(), begin, index, block.length)));
+                        }
+
+                        conn.reset_unrequested_blocks();
                         block.data = Some(block_data);
+                        client.count(Counter::BlockReceived, 1);
 
                         Ok(Some(block))
                     },
+                    // a late block of a piece given up on, or a peer sending garbage
                     None => {
-                        println_thread!("Received block was not requested");
+                        debug!("Received block {} of piece {} that was not requested", begin, index);
+
+                        // late answers to a whole pipeline are expected after giving a piece up
+                        let allowed = self.max_concurrent_requests + Self::MAX_UNREQUESTED_BLOCKS;
+
+                        if conn.add_unrequested_block() as usize > allowed {
+                            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                      format!("Peer sent more than {} blocks in a row we didn't request", allowed)));
+                        }
 
                         Ok(None)
                     }
                 }
             },
+            Message::Reject(index, begin, _) if index == self.index => {
+                let block_index = self.requested_blocks.iter()
+                    .position(|b| b.index == index && b.begin == begin);
+
+                // the block won't come, request it again or give the piece up
+                if let Some(block_index) = block_index {
+                    let block = self.requested_blocks.remove(block_index);
+
+                    self.block_queue.push(block);
+                    self.rejected_blocks += 1;
+                    client.count(Counter::BlockRejected, 1);
+                }
+
+                Ok(None)
+            },
             Message::Have(index) => {
-                conn.set_piece(&index);
+                if !conn.has_piece(&index) {
+                    conn.set_piece(&index, client.torrent.piece_count());
+                    client.torrent.strategy.update_availability(index, true);
+                }
 
                 Ok(None)
             },
             Message::Choke => {
-                conn.chocked = true;
+                set_choked(conn, client, true);
 
                 Ok(None)
             },
             Message::Unchoke => {
-                conn.chocked = false;
+                set_choked(conn, client, false);
 
                 Ok(None)
             },
@@ -208,45 +348,51 @@ impl PieceState {
     }
 
     fn can_send_request(&self) -> bool {
-        self.requested_blocks.len() < PieceState::MAX_CONCURRENT_REQUESTS
+        self.requested_blocks.len() < self.max_concurrent_requests
     }
 
-    // TODO: handle Option
+    // The block's length was checked against the request, so it fits its range exactly
     fn store_block_in_buffer(&mut self, block: Block) {
-        self.buf.splice(block.begin as usize..block.end as usize, block.data.unwrap());
+        if let Some(data) = block.data {
+            self.buf[block.begin as usize..block.end as usize].copy_from_slice(&data);
+        }
     }
+}
 
-    fn copy_to_file(&self, file: &mut File) -> io::Result<()> {
-        file.seek(SeekFrom::Start(self.begin as u64))?;
-        file.write_all(&self.buf)?;
-
-        Ok(())
+// Keeps the client's count of peers unchoking us in step with the connection
+fn set_choked(conn: &mut Connection, client: &Client, choked: bool) {
+    if conn.chocked != choked {
+        conn.chocked = choked;
+        client.set_unchoked(!choked);
     }
 }
 
 #[derive(Debug)]
-enum DownloadPieceError<'a> {
-    WrongHash(IntegrityError<'a>),
+enum DownloadPieceError {
+    WrongHash(IntegrityError),
+    Rejected, // too many of its requests rejected
     IOError(io::Error)
 }
 
-impl<'a> fmt::Display for DownloadPieceError<'a> {
+impl fmt::Display for DownloadPieceError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Self::WrongHash(e) =>
                 write!(f, "{}", e),
-            Self::IOError(..) =>
-                write!(f, "Error sending message")
+            Self::Rejected =>
+                write!(f, "Peer rejected too many requests for the piece"),
+            Self::IOError(e) =>
+                write!(f, "Error sending message: {}", e)
         }
     }
 }
-impl<'a> From<IntegrityError<'a>> for DownloadPieceError<'a> {
+impl From<IntegrityError> for DownloadPieceError {
     fn from(err: IntegrityError) -> DownloadPieceError {
         DownloadPieceError::WrongHash(err)
     }
 }
-impl<'a> From<io::Error> for DownloadPieceError<'a> {
-    fn from(err: io::Error) -> DownloadPieceError<'a> {
+impl From<io::Error> for DownloadPieceError {
+    fn from(err: io::Error) -> DownloadPieceError {
         DownloadPieceError::IOError(err)
     }
 }
@@ -0,0 +1,13 @@
+pub mod connection;
+pub mod message;
+pub mod torrent;
+pub mod bitfield;
+pub mod download_worker;
+pub mod client;
+pub mod config;
+pub mod lsd;
+pub mod piece_strategy;
+pub mod webseed;
+pub mod stream;
+pub mod sink;
+pub mod utils;
@@ -0,0 +1,133 @@
+use std::net::{UdpSocket, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::{Duration, Instant};
+use std::sync::mpsc::Sender;
+use std::{io, str};
+use rand::Rng;
+use socket2::{Socket, Domain, Type, Protocol};
+use log::warn;
+use crate::connection::Peer;
+use crate::client::Client;
+use crate::utils::to_hex;
+
+// Local Service Discovery (BEP 14), finds peers of the same torrent on the LAN
+// through multicast announces, without a tracker
+pub struct LocalDiscovery {
+    socket: UdpSocket,
+    info_hash: String, // hex encoded
+    port: u16,
+    cookie: String // tells our own announces apart when they loop back
+}
+
+impl LocalDiscovery {
+    const MULTICAST_ADDR: Ipv4Addr = Ipv4Addr::new(239, 192, 152, 143);
+    const PORT: u16 = 6771;
+    const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+    const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+    pub fn new(info_hash: &[u8], port: u16) -> io::Result<LocalDiscovery> {
+        let socket = Self::bind()?;
+
+        socket.join_multicast_v4(&Self::MULTICAST_ADDR, &Ipv4Addr::UNSPECIFIED)?;
+        socket.set_read_timeout(Some(Self::POLL_INTERVAL))?;
+
+        Ok(LocalDiscovery {
+            socket,
+            info_hash: to_hex(info_hash),
+            port,
+            cookie: format!("{:016x}", rand::thread_rng().gen::<u64>())
+        })
+    }
+
+    // Every torrent has its own discovery bound to the same port, and so may other
+    // clients on this machine, so the address is shared. Multicast datagrams reach
+    // all the sockets bound to it.
+    fn bind() -> io::Result<UdpSocket> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+
+        socket.set_reuse_address(true)?;
+        #[cfg(unix)]
+        socket.set_reuse_port(true)?;
+        socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, Self::PORT).into())?;
+
+        Ok(socket.into())
+    }
+
+    // Announces periodically and sends every peer found until the client is done or shutting down
+    pub fn run(&self, client: &Client, peers: Sender<Peer>) {
+        let mut last_announce: Option<Instant> = None;
+
+        while !client.is_done() && !client.is_shutting_down() {
+            if last_announce.is_none_or(|announce| announce.elapsed() >= Self::ANNOUNCE_INTERVAL) {
+                if let Err(e) = self.announce() {
+                    warn!("Error sending local peer discovery announce: {}", e);
+                }
+
+                last_announce = Some(Instant::now());
+            }
+
+            if let Some(peer) = self.receive() {
+                if peers.send(peer).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    fn announce(&self) -> io::Result<()> {
+        let message = format!("BT-SEARCH * HTTP/1.1\r\nHost: {}:{}\r\nPort: {}\r\nInfohash: {}\r\ncookie: {}\r\n\r\n\r\n",
+                              Self::MULTICAST_ADDR, Self::PORT, self.port, self.info_hash, self.cookie);
+
+        self.socket.send_to(message.as_bytes(), (Self::MULTICAST_ADDR, Self::PORT))?;
+
+        Ok(())
+    }
+
+    // Waits up to POLL_INTERVAL for another peer announcing our torrent
+    fn receive(&self) -> Option<Peer> {
+        let mut buf = [0; 1500];
+        let (len, from) = self.socket.recv_from(&mut buf).ok()?;
+        let ip = match from {
+            SocketAddr::V4(addr) => *addr.ip(),
+            SocketAddr::V6(_) => return None
+        };
+        let mut lines = str::from_utf8(&buf[..len]).ok()?.lines();
+        let mut port = None;
+        let mut same_torrent = false;
+        let mut ours = false;
+
+        if lines.next()? != "BT-SEARCH * HTTP/1.1" {
+            return None;
+        }
+
+        // an announce can carry several Infohash headers
+        for (name, value) in lines.filter_map(|line| line.split_once(':')) {
+            let value = value.trim();
+
+            match name.trim().to_ascii_lowercase().as_str() {
+                "port" => port = value.parse::<u16>().ok(),
+                "infohash" => same_torrent |= value.eq_ignore_ascii_case(&self.info_hash),
+                "cookie" => ours = value == self.cookie,
+                _ => {}
+            }
+        }
+
+        if ours || !same_torrent {
+            return None;
+        }
+
+        Some(Peer::from(SocketAddrV4::new(ip, port?)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn several_torrents_share_the_port() {
+        let first = LocalDiscovery::new(&[1; 20], 6881).unwrap();
+        let second = LocalDiscovery::new(&[2; 20], 6881).unwrap();
+
+        assert_ne!(first.cookie, second.cookie);
+    }
+}
@@ -1,61 +1,977 @@
-use std::env;
+use std::{env, io};
+use std::error::Error;
 use std::process::exit;
-use std::sync::Arc;
-use crate::torrent::Torrent;
-use crate::download_worker::DownloaderWorker;
-use crate::client::Client;
-use crate::connection::Connection;
-
-mod connection;
-mod message;
-mod torrent;
-mod download_worker;
-mod utils;
-mod client;
+use std::str::FromStr;
+use std::path::PathBuf;
+use std::net::SocketAddr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread::{self, JoinHandle, ScopedJoinHandle};
+use std::time::{Duration, Instant};
+use bittorrent_client::torrent::{Torrent, OpenTorrentError};
+use bittorrent_client::download_worker::DownloaderWorker;
+use bittorrent_client::client::{Client, TrackerEvent, Counter};
+use bittorrent_client::connection::{Connection, ConnectionError, TrackerResponse, Peer};
+use bittorrent_client::utils::{is_safe_file_name, format_eta};
+use bittorrent_client::config::{DownloadConfig, DownloadOptions, SwarmConfig};
+use bittorrent_client::lsd::LocalDiscovery;
+use bittorrent_client::webseed::WebSeeder;
+use bittorrent_client::stream::StreamServer;
+use bittorrent_client::piece_strategy::PieceOrder;
+use log::{Log, Level, LevelFilter, Metadata, Record, trace, debug, info, warn, error};
+
+const USAGE: &str = "Usage: bittorrent-client <torrent file path | URL | -> [out path] [--torrent <torrent>]... [--timeout <seconds>] [--name <file name>] [--block-size <bytes>] [--pipeline <requests>] [--final-verify] [--check-md5] [--resume] [--part] [--max-download <bytes>] [--no-preallocate] [--files <index,...>] [--ipv6 <address>] [--write-cache <bytes>] [--retries <count>] [--peer <ip:port>]... [--lsd] [--numwant <peers>] [--max-peers <count>] [--connect-concurrency <count>] [--peer-target <count>] [--piece-order <fifo|sequential|rarest>] [--sequential] [--max-piece-retries <count>] [--verbose] [--quiet] [--no-compact] [--check-interval <seconds>] [--scrape] [--json] [--peer-id <id>] [--continue-on-error] [--serve <port>]";
+const TIMEOUT_EXIT_CODE: i32 = 2;
+const VERIFY_FAILED_EXIT_CODE: i32 = 3;
+const BAD_PIECE_EXIT_CODE: i32 = 4;
+const QUOTA_EXIT_CODE: i32 = 5;
+const TRACKER_FAILED_EXIT_CODE: i32 = 6;
+const INCOMPLETE_EXIT_CODE: i32 = 7;
+const ERROR_EXIT_CODE: i32 = 1;
+const INTERRUPTED_EXIT_CODE: i32 = 130;
+const RETRY_BACKOFF: Duration = Duration::from_secs(2);
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+const JSON_STATUS_INTERVAL: Duration = Duration::from_secs(1);
+const CHECK_SAMPLE_SIZE: usize = 4; // completed pieces re-hashed every --check-interval
+const PEER_ID_ENV: &str = "BITTORRENT_PEER_ID"; // used when --peer-id isn't given
+const CHOKED_STALL_TIMEOUT: Duration = Duration::from_secs(60); // every peer choking us and no block for this long
+const TRACKER_RETRY_INTERVAL: Duration = Duration::from_secs(30); // between first announces with --continue-on-error
+
+struct Args {
+    torrent_paths: Vec<String>, // downloaded concurrently
+    output: DownloadOptions,
+    timeout: Option<Duration>,
+    final_verify: bool,
+    check_md5: bool, // check the files against the md5sums in the torrent once done
+    lsd: bool,
+    scrape: bool, // only print the swarm counts
+    json: bool, // status lines in JSON instead of the log
+    continue_on_error: bool, // keep retrying the first announce instead of giving up
+    serve: Option<u16>, // port to stream the download from over HTTP
+    peers: Vec<Peer>, // skips the tracker when not empty
+    swarm: SwarmConfig,
+    log_level: LevelFilter,
+    config: DownloadConfig
+}
+
+// Prints our log records to stdout, the debug and trace ones prefixed with
+// their thread, named after the peer or torrent they are about
+struct Logger;
+
+// Limits shared by the downloads of every torrent
+struct Limits {
+    deadline: Option<Instant>,
+    swarm: SwarmConfig,
+    peers: AtomicUsize, // connections open or in progress across all torrents
+    handshakes: Mutex<usize>, // connection attempts in flight
+    handshake_done: Condvar
+}
+
+// How the download of a torrent ended, ordered from best to worst
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Outcome {
+    Finished,
+    VerifyFailed,
+    BadPiece,
+    QuotaReached,
+    TrackerFailed,
+    Incomplete,
+    TimedOut,
+    Interrupted
+}
 
 fn main() {
-    let (torrent_path, out_path) = read_paths();
+    let args = read_args();
+
+    log::set_logger(&Logger).expect("Error setting logger.");
+    log::set_max_level(args.log_level);
+
+    match run(args) {
+        Ok(Outcome::Finished) => {},
+        Ok(Outcome::VerifyFailed) => exit(VERIFY_FAILED_EXIT_CODE),
+        Ok(Outcome::BadPiece) => exit(BAD_PIECE_EXIT_CODE),
+        Ok(Outcome::QuotaReached) => exit(QUOTA_EXIT_CODE),
+        Ok(Outcome::TrackerFailed) => exit(TRACKER_FAILED_EXIT_CODE),
+        Ok(Outcome::Incomplete) => exit(INCOMPLETE_EXIT_CODE),
+        Ok(Outcome::TimedOut) => exit(TIMEOUT_EXIT_CODE),
+        Ok(Outcome::Interrupted) => exit(INTERRUPTED_EXIT_CODE),
+        Err(e) => {
+            error!("{}", e);
+            exit(ERROR_EXIT_CODE)
+        }
+    }
+}
+
+// Returns the worst outcome of all the downloads, errors are the ones stopping
+// before any download starts or a download thread panicking
+fn run(args: Args) -> Result<Outcome, Box<dyn Error>> {
+    if args.scrape {
+        scrape(&args)?;
+
+        return Ok(Outcome::Finished);
+    }
+
+    let limits = Arc::new(Limits {
+        deadline: args.timeout.map(|timeout| Instant::now() + timeout),
+        swarm: args.swarm.clone(),
+        peers: AtomicUsize::new(0),
+        handshakes: Mutex::new(0),
+        handshake_done: Condvar::new()
+    });
+    let downloads = args.torrent_paths.iter()
+        .map(|path| open_download(&args, path))
+        .collect::<Result<Vec<(Torrent, Arc<Client>)>, Box<dyn Error>>>()?;
+    let clients = downloads.iter()
+        .map(|(_, client)| client.clone())
+        .collect::<Vec<Arc<Client>>>();
+
+    set_interrupt_handler(&clients);
+
+    let (args, limits) = (&args, &limits);
+    let outcomes = thread::scope(|scope| {
+        let handlers = downloads.iter()
+            .map(|(torrent, client)| {
+                thread::Builder::new()
+                    .name(torrent.name.to_string())
+                    .spawn_scoped(scope, move || download(args, torrent, client, limits))
+                    .expect("Error starting download.")
+            })
+            .collect::<Vec<ScopedJoinHandle<Outcome>>>();
+
+        if args.json {
+            print_json_status(&downloads, &handlers);
+        } else if clients.len() > 1 {
+            print_total_progress(&clients, &handlers);
+        }
+
+        handlers.into_iter()
+            .map(|handler| handler.join().map_err(|_| "A download stopped unexpectedly".into()))
+            .collect::<Result<Vec<Outcome>, Box<dyn Error>>>()
+    })?;
+
+    Ok(outcomes.into_iter().max().unwrap_or(Outcome::Finished))
+}
+
+// Prints the swarm counts of every torrent without joining the swarms
+fn scrape(args: &Args) -> Result<(), OpenTorrentError> {
+    for path in &args.torrent_paths {
+        let torrent = open_torrent(path)?;
+
+        match Client::scrape(&torrent) {
+            Ok(stats) => println!("{}: seeders: {}, leechers: {}, downloaded {} times",
+                                  torrent.name, stats.complete, stats.incomplete, stats.downloaded),
+            Err(e) => error!("{}: error scraping tracker: {}", torrent.name, e)
+        }
+    }
+
+    Ok(())
+}
+
+fn open_download(args: &Args, path: &str) -> Result<(Torrent, Arc<Client>), Box<dyn Error>> {
+    let torrent = open_torrent(path)?;
+
+    if let Some(files) = &args.config.files {
+        if let Some(index) = files.iter().find(|&&index| index >= torrent.file_count()) {
+            exit_with_usage(&format!("File index {} is out of range, the torrent has {} files.", index, torrent.file_count()));
+        }
+    }
+
+    let client = Client::new(&torrent, &args.output, args.config.clone())
+        .map_err(|e| format!("Error creating the files of {}: {}", torrent.name, e))?;
 
-    run(torrent_path, out_path);
+    Ok((torrent, Arc::new(client)))
 }
 
-fn run(torrent_path: String, out_path: Option<String>) {
-    let torrent = Torrent::open(torrent_path).unwrap();
-    let client = Arc::new(Client::new(&torrent, out_path));
-    let tracker = client.get_tracker(&torrent).unwrap();
+fn download(args: &Args, torrent: &Torrent, client: &Arc<Client>, limits: &Arc<Limits>) -> Outcome {
+    let use_tracker = args.peers.is_empty() && !torrent.announce.is_empty();
+    let (discovered_tx, discovered) = mpsc::channel();
     let mut workers = Vec::new();
 
-    println!("{}",&torrent);
-    println!("Number of peers: {}", &tracker.peers.len());
+    info!("{}", &torrent);
+    info!("Saving to {}", client.out_path.display());
 
-    for peer in tracker.peers {
-        match Connection::new(&client, peer) {
-            Ok(conn) => {
-                let handler = DownloaderWorker::new(client.clone(), conn)
-                    .start();
+    // the output path is the one of this run, wherever the previous run saved to
+    if client.config.resume {
+        match client.resume() {
+            Ok(kept) => info!("Resuming, {} of {} pieces were already in {}", kept, client.torrent.total_pieces, client.out_path.display()),
+            Err(e) => error!("Error checking the existing file, downloading it all again: {}", e)
+        }
+    }
 
-                workers.push(handler);
-            },
-            Err(_) => continue
+    let streaming = args.serve.is_some_and(|port| start_stream_server(client.clone(), torrent, port));
+
+    if client.torrent.total_pieces < torrent.piece_count() {
+        info!("Downloading {} of {} pieces for the selected files", client.torrent.total_pieces, torrent.piece_count());
+    }
+
+    let (peers, announce_interval) = if use_tracker {
+        let tracker = match announce_started(args, torrent, client, limits) {
+            Ok(tracker) => tracker,
+            Err(outcome) => return outcome
+        };
+
+        info!("Number of peers: {}", &tracker.peers.len());
+        print_swarm_health(&tracker);
+
+        let announce_interval = tracker.announce_interval();
+
+        (tracker.peers, Some(announce_interval))
+    } else if args.peers.is_empty() {
+        info!("No tracker to ask for peers");
+
+        (Vec::new(), None)
+    } else {
+        info!("Connecting to {} peers given, skipping the tracker", args.peers.len());
+
+        (args.peers.clone(), None)
+    };
+
+    if args.lsd {
+        if torrent.private {
+            info!("Local peer discovery is disabled for private torrents");
+        } else {
+            workers.push(start_local_discovery(client.clone(), discovered_tx));
         }
     }
 
+    if !torrent.web_seeds.is_empty() {
+        workers.push(start_web_seeding(client.clone(), torrent));
+    }
+
+    if let Some(interval) = client.config.check_interval {
+        workers.push(start_integrity_check(client.clone(), torrent, interval));
+    }
+
+    connect_peers(client, peers, &mut workers, limits);
+
+    let timed_out = supervise(client, torrent, &mut workers, limits, announce_interval, &discovered);
+
     for handler in workers {
-        handler.join().expect("Error joining worker with main thread.");
+        if handler.join().is_err() {
+            error!("A worker stopped unexpectedly");
+        }
+    }
+
+    // the workers stop on their own once over the quota, possibly before `supervise` noticed
+    if client.is_over_quota() && !client.is_done() {
+        client.shutdown();
+    }
+
+    let stats = client.stats();
+
+    info!("{}", stats);
+
+    let traffic = client.get_traffic();
+
+    info!("Sent {:.2} MiB and received {:.2} MiB from peers, ratio {}",
+          traffic.sent() as f64 / (1024.0 * 1024.0),
+          traffic.received() as f64 / (1024.0 * 1024.0),
+          traffic.ratio().map_or_else(|| "-".to_string(), |ratio| format!("{:.2}", ratio)));
+
+    if stats.wrong_swarm_peers > 0 {
+        warn!("{} peers were serving another torrent, the tracker may be handing out peers of the wrong swarm", stats.wrong_swarm_peers);
+    }
+
+    // an unfinished multi-file download can still have some files ready to use
+    if torrent.file_count() > 1 && !client.is_done() {
+        for (path, progress) in client.file_progress(torrent) {
+            info!("{}: {:.1}%", path, progress * 100.0);
+        }
+    }
+
+    if client.is_shutting_down() {
+        stop(client, torrent, use_tracker);
+
+        if let Some(index) = client.torrent.bad_piece() {
+            error!("Download aborted, piece {} failed its hash check {} times.", index, client.config.max_piece_failures);
+
+            return Outcome::BadPiece;
+        }
+
+        if timed_out {
+            error!("Download timed out after {} seconds.", args.timeout.unwrap().as_secs());
+
+            return Outcome::TimedOut;
+        }
+
+        if client.is_over_quota() {
+            error!("Download stopped after receiving the {} bytes allowed by --max-download.", client.config.max_download.unwrap());
+
+            return Outcome::QuotaReached;
+        }
+
+        return Outcome::Interrupted;
+    }
+
+    // every worker ended, e.g. the swarm ran out of peers, before the last piece
+    if !client.is_done() {
+        stop(client, torrent, use_tracker);
+        error!("Download ended unfinished, {} of {} pieces done.", *client.get_done_pieces(), client.torrent.total_pieces);
+
+        return Outcome::Incomplete;
+    }
+
+    if let Err(e) = client.flush_write_cache() {
+        error!("Error writing cached pieces: {}", e);
+    }
+
+    if args.final_verify && !final_verify(client, torrent) {
+        return Outcome::VerifyFailed;
+    }
+
+    if args.check_md5 && !check_md5sums(client, torrent) {
+        return Outcome::VerifyFailed;
+    }
+
+    if let Err(e) = client.finish_files() {
+        error!("Error renaming the downloaded .part files: {}", e);
+    }
+
+    if streaming {
+        info!("Download finished, still streaming it until Ctrl-C is pressed");
+
+        while !client.is_shutting_down() {
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    Outcome::Finished
+}
+
+// Prints the progress of all the torrents together until every download ends
+fn print_total_progress(clients: &[Arc<Client>], handlers: &[ScopedJoinHandle<Outcome>]) {
+    let mut last_print = Instant::now();
+
+    while handlers.iter().any(|handler| !handler.is_finished()) {
+        if last_print.elapsed() >= PROGRESS_INTERVAL {
+            let done = clients.iter().map(|client| client.bytes_done()).sum::<u64>();
+            let total = clients.iter().map(|client| client.torrent.length).sum::<u64>();
+            let rate = clients.iter().map(|client| client.overall_rate()).sum::<f64>();
+            let eta = (rate > 0.0).then(|| Duration::from_secs_f64(total.saturating_sub(done) as f64 / rate));
+
+            info!("All torrents: {} / {} bytes, {:.2} MiB/s, ETA {}",
+                  done, total, rate / (1024.0 * 1024.0), format_eta(eta));
+            last_print = Instant::now();
+        }
+
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+// Prints the status of every torrent as a JSON object per line, until the downloads end
+fn print_json_status(downloads: &[(Torrent, Arc<Client>)], handlers: &[ScopedJoinHandle<Outcome>]) {
+    loop {
+        let finished = handlers.iter().all(|handler| handler.is_finished());
+
+        for (torrent, client) in downloads {
+            println!("{}", serde_json::to_string(&client.status(&torrent.name)).unwrap());
+        }
+
+        if finished {
+            break;
+        }
+
+        thread::sleep(JSON_STATUS_INTERVAL);
+    }
+}
+
+// Re-hashes the whole file to catch pieces corrupted after their own check
+fn final_verify(client: &Client, torrent: &Torrent) -> bool {
+    info!("Verifying downloaded file...");
+
+    match client.verify_file(torrent) {
+        Ok(bad_pieces) if bad_pieces.is_empty() => {
+            info!("All {} pieces verified.", torrent.piece_count());
+
+            true
+        },
+        Ok(bad_pieces) => {
+            error!("Verification failed, corrupted pieces: {:?}", bad_pieces);
+
+            false
+        },
+        Err(e) => {
+            error!("Error verifying file: {}", e);
+
+            false
+        }
+    }
+}
+
+fn check_md5sums(client: &Client, torrent: &Torrent) -> bool {
+    let checked = torrent.file_md5sums().iter().flatten().count();
+
+    if checked == 0 {
+        info!("The torrent has no md5sum to check.");
+
+        return true;
+    }
+
+    match client.verify_md5sums(torrent) {
+        Ok(mismatches) if mismatches.is_empty() => {
+            info!("All {} md5sums match.", checked);
+
+            true
+        },
+        Ok(mismatches) => {
+            for mismatch in mismatches {
+                error!("{}", mismatch);
+            }
+
+            false
+        },
+        Err(e) => {
+            error!("Error checking md5sums: {}", e);
+
+            false
+        }
+    }
+}
+
+// Joins the swarm. A tracker that can't be reached fails the download, or with
+// --continue-on-error is asked again every TRACKER_RETRY_INTERVAL until it answers,
+// the deadline passes or the client is interrupted.
+fn announce_started(args: &Args, torrent: &Torrent, client: &Client, limits: &Limits) -> Result<TrackerResponse, Outcome> {
+    loop {
+        let e = match client.send_tracker_request(torrent, Some(TrackerEvent::Started)) {
+            Ok(tracker) => return Ok(tracker),
+            Err(e) => e
+        };
+
+        if !args.continue_on_error {
+            error!("Error contacting tracker: {}", e);
+
+            return Err(Outcome::TrackerFailed);
+        }
+
+        warn!("Error contacting tracker, retrying in {} seconds: {}", TRACKER_RETRY_INTERVAL.as_secs(), e);
+
+        let retry_at = Instant::now() + TRACKER_RETRY_INTERVAL;
+
+        while Instant::now() < retry_at {
+            if limits.is_past_deadline() {
+                error!("Download timed out after {} seconds.", args.timeout.unwrap().as_secs());
+
+                return Err(Outcome::TimedOut);
+            }
+
+            if client.is_shutting_down() {
+                return Err(Outcome::Interrupted);
+            }
+
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+}
+
+// Leaves the swarm after an aborted download, keeping what was written so far
+fn stop(client: &Client, torrent: &Torrent, use_tracker: bool) {
+    if use_tracker {
+        if let Err(e) = client.send_tracker_request(torrent, Some(TrackerEvent::Stopped)) {
+            warn!("Error sending stopped event to tracker: {}", e);
+        }
     }
+
+    if let Err(e) = client.sync_file() {
+        error!("Error flushing file: {}", e);
+    }
+}
+
+// First Ctrl-C asks the workers of every torrent to stop, the second one exits right away
+fn set_interrupt_handler(clients: &[Arc<Client>]) {
+    let shutdown_flags = clients.iter()
+        .map(|client| client.get_shutdown_flag())
+        .collect::<Vec<_>>();
+    let interrupted = AtomicBool::new(false);
+
+    ctrlc::set_handler(move || {
+        if interrupted.swap(true, Ordering::Relaxed) {
+            exit(INTERRUPTED_EXIT_CODE);
+        }
+
+        for shutdown in &shutdown_flags {
+            shutdown.store(true, Ordering::Relaxed);
+        }
+
+        warn!("Shutting down, press Ctrl-C again to exit immediately.");
+    }).expect("Error setting Ctrl-C handler.");
+}
+
+// Starts a worker for every peer we aren't already connected to, while the peer limit allows it
+fn connect_peers(client: &Arc<Client>, peers: Vec<Peer>, workers: &mut Vec<JoinHandle<()>>, limits: &Arc<Limits>) {
+    for peer in peers {
+        if limits.is_past_deadline() || client.is_shutting_down() || client.is_done() {
+            break;
+        }
+
+        // enough peers are sending us pieces
+        if client.unchoked_peers() >= limits.swarm.peer_target {
+            break;
+        }
+
+        let addr = SocketAddr::from(peer);
+
+        if !client.add_peer(addr) {
+            continue;
+        }
+
+        if !limits.acquire_peer() {
+            client.remove_peer(&addr);
+
+            break;
+        }
+
+        workers.push(start_worker(client.clone(), peer, limits.clone()));
+    }
+}
+
+// Runs a worker for the peer in its own thread. When the connection fails it
+// reconnects with an exponential backoff, up to `peer_retries` times.
+fn start_worker(client: Arc<Client>, peer: Peer, limits: Arc<Limits>) -> JoinHandle<()> {
+    let addr = SocketAddr::from(peer);
+
+    thread::Builder::new()
+        .name(addr.ip().to_string())
+        .spawn(move || {
+            let mut retries = 0;
+
+            loop {
+                trace!("Connecting to {}", addr);
+
+                limits.begin_handshake();
+
+                let connection = Connection::new(&client, peer);
+
+                limits.end_handshake();
+
+                let failed = match connection {
+                    Ok(conn) => {
+                        trace!("Connected to {}", addr);
+
+                        match DownloaderWorker::new(client.clone(), conn).run() {
+                            Ok(()) => false,
+                            // the peer misbehaved, it isn't worth reconnecting to
+                            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                                debug!("Dropping {}: {}", addr, e);
+
+                                false
+                            },
+                            Err(_) => true
+                        }
+                    },
+                    Err(ConnectionError::IOError(e)) => {
+                        match e.kind() {
+                            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock =>
+                                trace!("Timed out connecting to {}", addr),
+                            _ => trace!("Connection to {} failed: {}", addr, e)
+                        }
+
+                        true
+                    },
+                    // wrong torrent, ourselves or encryption required, retrying won't help
+                    Err(e @ ConnectionError::WrongHash(_)) => {
+                        debug!("Handshake with {} failed: {}", addr, e);
+                        client.count(Counter::WrongSwarmPeer, 1);
+
+                        false
+                    },
+                    Err(e) => {
+                        debug!("Handshake with {} failed: {}", addr, e);
+
+                        false
+                    }
+                };
+
+                if !failed || retries >= client.config.peer_retries || client.is_done() || client.is_shutting_down() {
+                    break;
+                }
+
+                retries += 1;
+                wait_unless_shutdown(&client, RETRY_BACKOFF * 2u32.pow((retries - 1).min(6)));
+            }
+
+            client.remove_peer(&addr);
+            limits.release_peer();
+        }).expect("Error starting worker.")
+}
+
+// Finds peers on the LAN in the background, they are connected to by `supervise`
+fn start_local_discovery(client: Arc<Client>, discovered: Sender<Peer>) -> JoinHandle<()> {
+    thread::Builder::new()
+        .name("lsd".to_string())
+        .spawn(move || {
+            match LocalDiscovery::new(&client.torrent.info_hash, client.port) {
+                Ok(lsd) => lsd.run(&client, discovered),
+                Err(e) => error!("Error starting local peer discovery: {}", e)
+            }
+        }).expect("Error starting local peer discovery.")
 }
 
-fn read_paths() -> (String, Option<String>) {
-    let args = env::args().collect::<Vec<String>>();
-    let torrent_path = args.get(1);
-    let out_path = args.get(2).cloned();
+// Downloads from the torrent's web seeds in the background whenever the peers stall
+fn start_web_seeding(client: Arc<Client>, torrent: &Torrent) -> JoinHandle<()> {
+    let seeder = WebSeeder::new(torrent);
+
+    thread::Builder::new()
+        .name("webseed".to_string())
+        .spawn(move || {
+            match seeder {
+                Ok(seeder) => seeder.run(&client),
+                Err(e) => error!("Error starting web seeding: {}", e)
+            }
+        }).expect("Error starting web seeding.")
+}
 
-    match torrent_path {
-        Some(path) => (path.to_owned(), out_path),
-        None => {
-            println!("No torrent path found.\n\
-                      Usage: bittorrent-client <torrent file path> [out path]");
-            exit(0);
+// Serves the download over HTTP on localhost until the process exits, returns false if it can't listen
+fn start_stream_server(client: Arc<Client>, torrent: &Torrent, port: u16) -> bool {
+    let server = match StreamServer::bind(port, torrent) {
+        Ok(server) => Arc::new(server),
+        Err(e) => {
+            error!("Error starting the stream server on port {}: {}", port, e);
+
+            return false;
         }
+    };
+
+    info!("Streaming on http://127.0.0.1:{}/", port);
+
+    thread::Builder::new()
+        .name("stream".to_string())
+        .spawn(move || server.run(client))
+        .expect("Error starting the stream server.");
+
+    true
+}
+
+// Re-hashes a few completed pieces every `interval` until the download ends,
+// the ones corrupted on disk are downloaded again
+fn start_integrity_check(client: Arc<Client>, torrent: &Torrent, interval: Duration) -> JoinHandle<()> {
+    let pieces = Vec::from(torrent.create_piece_queue());
+
+    thread::Builder::new()
+        .name("check".to_string())
+        .spawn(move || {
+            loop {
+                wait_unless_shutdown(&client, interval);
+
+                if client.is_done() || client.is_shutting_down() {
+                    break;
+                }
+
+                match client.recheck_pieces(&pieces, CHECK_SAMPLE_SIZE) {
+                    Ok(corrupted) => {
+                        for index in corrupted {
+                            warn!("Piece {} is corrupted on disk, downloading it again", index);
+                        }
+                    },
+                    Err(e) => {
+                        error!("Error re-checking pieces: {}", e);
+                    }
+                }
+            }
+        }).expect("Error starting integrity check.")
+}
+
+// Sleeps for `delay`, cut short when the download ends
+fn wait_unless_shutdown(client: &Client, delay: Duration) {
+    let until = Instant::now() + delay;
+
+    while Instant::now() < until && !client.is_shutting_down() && !client.is_done() {
+        thread::sleep(Duration::from_millis(100));
     }
 }
+
+// Blocks until every worker finishes, re-announcing to the tracker periodically
+// and connecting to the new peers it returns, unless `announce_interval` is None.
+// With a tracker it goes on after the workers finish, until the download is done
+// or shutting down, since that's when fresh peers are needed the most.
+// It re-announces early when every peer has been choking us for CHOKED_STALL_TIMEOUT.
+// Every pass pings the idle peers and drops the dead ones, whatever their workers
+// are doing. Peers found by local discovery are connected to as they arrive. If the deadline
+// passes the workers are told to shut down and `true` is returned.
+fn supervise(client: &Arc<Client>,
+             torrent: &Torrent,
+             workers: &mut Vec<JoinHandle<()>>,
+             limits: &Arc<Limits>,
+             mut announce_interval: Option<Duration>,
+             discovered: &Receiver<Peer>) -> bool {
+    let mut last_announce = Instant::now();
+    let mut blocks_received = client.stats().blocks_received;
+    let mut last_block = Instant::now();
+
+    loop {
+        let working = workers.iter().any(|handler| !handler.is_finished());
+        let waiting_for_peers = announce_interval.is_some() && !client.is_done() && !client.is_shutting_down();
+
+        if !working && !waiting_for_peers {
+            break;
+        }
+
+        if limits.is_past_deadline() {
+            client.shutdown();
+
+            return true;
+        }
+
+        // the pieces started before the quota was reached are finished first
+        if client.is_over_quota() && !client.is_done() && client.pieces_in_progress() == 0 {
+            client.shutdown();
+        }
+
+        let received = client.stats().blocks_received;
+
+        if received != blocks_received || client.unchoked_peers() > 0 {
+            blocks_received = received;
+            last_block = Instant::now();
+        }
+
+        let all_choking = last_block.elapsed() >= CHOKED_STALL_TIMEOUT && client.peer_count() > 0 && !client.is_done();
+
+        if all_choking {
+            warn!("All {} peers are choking us and nothing was downloaded for {} seconds", client.peer_count(), CHOKED_STALL_TIMEOUT.as_secs());
+            last_block = Instant::now();
+        }
+
+        if announce_interval.is_some_and(|interval| all_choking || last_announce.elapsed() >= interval) {
+            // the choking peers may be all we have, numwant would be 0 then
+            let response = if all_choking {
+                client.request_fresh_peers(torrent)
+            } else {
+                client.send_tracker_request(torrent, None)
+            };
+
+            match response {
+                Ok(tracker) => {
+                    print_swarm_health(&tracker);
+                    announce_interval = Some(tracker.announce_interval());
+                    connect_peers(client, tracker.peers, workers, limits);
+                },
+                Err(e) => warn!("Error re-announcing to tracker: {}", e)
+            }
+
+            last_announce = Instant::now();
+        }
+
+        client.check_idle_peers();
+        connect_peers(client, discovered.try_iter().collect(), workers, limits);
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    false
+}
+
+fn print_swarm_health(tracker: &TrackerResponse) {
+    if let (Some(seeders), Some(leechers)) = (tracker.complete, tracker.incomplete) {
+        info!("Seeders: {}, leechers: {}", seeders, leechers);
+    }
+}
+
+// "-" reads the torrent from stdin and http(s) URLs are downloaded
+fn open_torrent(path: &str) -> Result<Torrent, OpenTorrentError> {
+    if path == "-" {
+        Torrent::from_reader(io::stdin())
+    } else if path.starts_with("http://") || path.starts_with("https://") {
+        Torrent::fetch(path)
+    } else {
+        Torrent::open(path)
+    }
+}
+
+impl Log for Logger {
+    // dependencies log too, only our records are shown
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level() && metadata.target().starts_with("bittorrent_client")
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        // stdout is left to the status lines, problems go to stderr
+        if record.level() <= Level::Warn {
+            eprintln!("{}", record.args());
+        } else if record.level() >= Level::Debug {
+            println!("Thread [{}]: {}", thread::current().name().unwrap_or("unnamed"), record.args());
+        } else {
+            println!("{}", record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+impl Limits {
+    fn is_past_deadline(&self) -> bool {
+        match self.deadline {
+            Some(deadline) => Instant::now() >= deadline,
+            None => false
+        }
+    }
+
+    // Takes a connection slot, false if all of them are taken
+    fn acquire_peer(&self) -> bool {
+        self.peers
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |peers| {
+                if peers >= self.swarm.max_connections {
+                    None
+                } else {
+                    Some(peers + 1)
+                }
+            })
+            .is_ok()
+    }
+
+    fn release_peer(&self) {
+        self.peers.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    // Waits until fewer than `connect_concurrency` connection attempts are in flight
+    fn begin_handshake(&self) {
+        let mut handshakes = self.handshakes.lock().unwrap();
+
+        while *handshakes >= self.swarm.connect_concurrency {
+            handshakes = self.handshake_done.wait(handshakes).unwrap();
+        }
+
+        *handshakes += 1;
+    }
+
+    fn end_handshake(&self) {
+        *self.handshakes.lock().unwrap() -= 1;
+        self.handshake_done.notify_one();
+    }
+}
+
+fn read_args() -> Args {
+    let mut args = env::args().skip(1);
+    let mut positional = Vec::new();
+    let mut timeout = None;
+    let mut output = DownloadOptions::default();
+    let mut config = DownloadConfig::default();
+    let mut final_verify = false;
+    let mut check_md5 = false;
+    let mut lsd = false;
+    let mut scrape = false;
+    let mut json = false;
+    let mut continue_on_error = false;
+    let mut serve = None;
+    let mut log_level = LevelFilter::Info;
+    let mut peers = Vec::new();
+    let mut extra_torrents = Vec::new();
+    let mut swarm = SwarmConfig::default();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--timeout" => {
+                let secs = next_value(&mut args, "--timeout expects a number of seconds.");
+
+                timeout = Some(Duration::from_secs(secs));
+            },
+            "--block-size" => config.block_size = next_value(&mut args, "--block-size expects a number of bytes."),
+            "--pipeline" => config.max_concurrent_requests = next_value(&mut args, "--pipeline expects a number of requests."),
+            "--final-verify" => final_verify = true,
+            "--check-md5" => check_md5 = true,
+            "--resume" => config.resume = true,
+            "--part" => output.part_suffix = true,
+            "--max-download" => config.max_download = Some(next_value(&mut args, "--max-download expects a number of bytes.")),
+            "--verbose" | "-v" => log_level = LevelFilter::Trace,
+            "--quiet" | "-q" => log_level = LevelFilter::Warn,
+            "--no-compact" => config.compact = false,
+            "--lsd" => lsd = true,
+            "--scrape" => scrape = true,
+            "--json" => json = true,
+            "--continue-on-error" => continue_on_error = true,
+            "--serve" => serve = Some(next_value(&mut args, "--serve expects a port.")),
+            "--no-preallocate" => config.preallocate = false,
+            "--files" => {
+                let files = next_value::<String>(&mut args, "--files expects a list of file indexes.")
+                    .split(',')
+                    .map(|index| index.trim().parse::<usize>())
+                    .collect::<Result<Vec<usize>, _>>()
+                    .unwrap_or_else(|_| exit_with_usage("--files expects a comma separated list of file indexes."));
+
+                config.files = Some(files);
+            },
+            "--ipv6" => config.announce_ipv6 = Some(next_value(&mut args, "--ipv6 expects an IPv6 address.")),
+            "--write-cache" => config.write_cache_size = next_value(&mut args, "--write-cache expects a number of bytes."),
+            "--peer" => peers.push(next_value(&mut args, "--peer expects an IPv4 address and port, e.g. 192.168.1.10:6881.")),
+            "--numwant" => config.numwant = next_value(&mut args, "--numwant expects a number of peers."),
+            "--torrent" => extra_torrents.push(next_value(&mut args, "--torrent expects a torrent file path or URL.")),
+            "--max-peers" => swarm.max_connections = next_value(&mut args, "--max-peers expects a number of peers."),
+            "--connect-concurrency" => swarm.connect_concurrency = next_value(&mut args, "--connect-concurrency expects a number of connection attempts."),
+            "--peer-target" => swarm.peer_target = next_value(&mut args, "--peer-target expects a number of peers."),
+            "--sequential" => config.piece_order = PieceOrder::Sequential,
+            "--piece-order" => config.piece_order = next_value(&mut args, "--piece-order expects fifo, sequential or rarest."),
+            "--max-piece-retries" => config.max_piece_failures = next_value(&mut args, "--max-piece-retries expects a number of failures."),
+            "--check-interval" => {
+                let secs = next_value(&mut args, "--check-interval expects a number of seconds.");
+
+                config.check_interval = Some(Duration::from_secs(secs));
+            },
+            "--peer-id" => config.peer_id = Some(next_value::<String>(&mut args, "--peer-id expects a 20 byte id.").into_bytes()),
+            "--retries" => config.peer_retries = next_value(&mut args, "--retries expects a number of reconnections."),
+            "--name" => {
+                let file_name = args.next()
+                    .filter(|file_name| is_safe_file_name(file_name))
+                    .unwrap_or_else(|| exit_with_usage("--name expects a file name without directories."));
+
+                output.name_override = Some(file_name);
+            },
+            // "-" is the torrent read from stdin
+            flag if flag.starts_with('-') && flag != "-" => exit_with_usage(&format!("Unknown option {}.", flag)),
+            _ => positional.push(arg)
+        }
+    }
+
+    // the flag takes precedence over the environment
+    if config.peer_id.is_none() {
+        config.peer_id = env::var(PEER_ID_ENV).ok().map(String::into_bytes);
+    }
+
+    if let Err(e) = config.validate().and_then(|_| swarm.validate()) {
+        exit_with_usage(&e.to_string());
+    }
+
+    // the name, file indexes and stream port only make sense for a single torrent
+    if !extra_torrents.is_empty() && (output.name_override.is_some() || config.files.is_some() || serve.is_some()) {
+        exit_with_usage("--name, --files and --serve can't be used with several torrents.");
+    }
+
+    let mut positional = positional.into_iter();
+
+    match positional.next() {
+        Some(torrent_path) => Args {
+            torrent_paths: [vec![torrent_path], extra_torrents].concat(),
+            output: DownloadOptions {
+                out_dir: positional.next().map_or(output.out_dir, PathBuf::from),
+                ..output
+            },
+            timeout,
+            final_verify,
+            check_md5,
+            lsd,
+            scrape,
+            json,
+            continue_on_error,
+            serve,
+            peers,
+            swarm,
+            // the JSON lines are the only output on stdout, warnings and errors still reach stderr
+            log_level: if json { LevelFilter::Warn } else { log_level },
+            config
+        },
+        None => exit_with_usage("No torrent path found.")
+    }
+}
+
+fn next_value<T: FromStr>(args: &mut impl Iterator<Item = String>, error: &str) -> T {
+    args.next()
+        .and_then(|value| value.parse::<T>().ok())
+        .unwrap_or_else(|| exit_with_usage(error))
+}
+
+fn exit_with_usage(msg: &str) -> ! {
+    eprintln!("{}\n{}", msg, USAGE);
+    exit(ERROR_EXIT_CODE);
+}
@@ -1,5 +1,9 @@
+use std::fmt;
+use std::error::Error;
+use std::convert::TryFrom;
 use byteorder::{BigEndian, ByteOrder};
 
+// `Debug` dumps the whole payload, `Display` summarizes it for logs
 #[derive(Debug)]
 pub enum Message {
     KeepAlive,
@@ -11,12 +15,31 @@ pub enum Message {
     Bitfield(Vec<u8>),
     Request(u32, u32, u32),
     Piece(u32, u32, Vec<u8>),
-    Cancel
+    Cancel,
+    SuggestPiece(u32), // fast extension (BEP 6)
+    Reject(u32, u32, u32), // fast extension (BEP 6)
+    AllowedFast(u32) // fast extension (BEP 6)
 }
 
 impl Message {
+    // Panics on unknown ids and truncated payloads, `try_from` reports them instead
     pub fn new(id: u8, payload: &[u8]) -> Message {
-        match id {
+        Self::parse(id, payload).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn parse(id: u8, payload: &[u8]) -> Result<Message, MessageError> {
+        let expected_len = match id {
+            0..=3 => Some(0),
+            4 | 13 | 17 => Some(4),
+            6 | 16 => Some(12),
+            _ => None
+        };
+
+        if expected_len.is_some_and(|len| len != payload.len()) || (id == 7 && payload.len() < 8) {
+            return Err(MessageError::BadLength(id, payload.len()));
+        }
+
+        let message = match id {
             0 => Message::Choke,
             1 => Message::Unchoke,
             2 => Message::Interested,
@@ -37,9 +60,21 @@ impl Message {
 
                 Message::Piece(index, begin, piece)
             },
+            // its fields aren't used, so its payload isn't checked
             8 => Message::Cancel,
-            _ => panic!("Bad message ID: {}", id)
-        }
+            13 => Message::SuggestPiece(BigEndian::read_u32(payload)),
+            16 => {
+                let index = BigEndian::read_u32(&payload[..4]);
+                let begin = BigEndian::read_u32(&payload[4..8]);
+                let length = BigEndian::read_u32(&payload[8..]);
+
+                Message::Reject(index, begin, length)
+            },
+            17 => Message::AllowedFast(BigEndian::read_u32(payload)),
+            _ => return Err(MessageError::UnknownId(id))
+        };
+
+        Ok(message)
     }
 
     pub fn serialize(self) -> Vec<u8> {
@@ -88,7 +123,34 @@ impl Message {
                 payload.extend(&b);
                 payload.extend(piece);
             },
-            Message::Cancel => payload.push(8)
+            Message::Cancel => payload.push(8),
+            Message::SuggestPiece(index) => {
+                let mut buf = [0; 4];
+
+                BigEndian::write_u32(&mut buf, index);
+                payload.push(13);
+                payload.extend(&buf);
+            },
+            Message::Reject(index, begin, len) => {
+                let mut i = [0; 4];
+                let mut b = [0; 4];
+                let mut l = [0; 4];
+
+                BigEndian::write_u32(&mut i, index);
+                BigEndian::write_u32(&mut b, begin);
+                BigEndian::write_u32(&mut l, len);
+                payload.push(16);
+                payload.extend(&i);
+                payload.extend(&b);
+                payload.extend(&l);
+            },
+            Message::AllowedFast(index) => {
+                let mut buf = [0; 4];
+
+                BigEndian::write_u32(&mut buf, index);
+                payload.push(17);
+                payload.extend(&buf);
+            }
         }
 
         BigEndian::write_u32(&mut len, payload.len() as u32);
@@ -98,3 +160,102 @@ impl Message {
         message
     }
 }
+
+// Parses a message body as framed on the wire without its length prefix: the id
+// followed by the payload. An empty body is a keep-alive.
+impl TryFrom<&[u8]> for Message {
+    type Error = MessageError;
+
+    fn try_from(body: &[u8]) -> Result<Message, MessageError> {
+        match body.split_first() {
+            Some((&id, payload)) => Self::parse(id, payload),
+            None => Ok(Message::KeepAlive)
+        }
+    }
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Message::KeepAlive => write!(f, "KeepAlive"),
+            Message::Choke => write!(f, "Choke"),
+            Message::Unchoke => write!(f, "Unchoke"),
+            Message::Interested => write!(f, "Interested"),
+            Message::NotInterested => write!(f, "NotInterested"),
+            Message::Have(index) => write!(f, "Have(index={})", index),
+            Message::Bitfield(bitfield) => write!(f, "Bitfield(len={} bytes)", bitfield.len()),
+            Message::Request(index, begin, len) =>
+                write!(f, "Request(index={}, begin={}, len={})", index, begin, len),
+            Message::Piece(index, begin, piece) =>
+                write!(f, "Piece(index={}, begin={}, len={})", index, begin, piece.len()),
+            Message::Cancel => write!(f, "Cancel"),
+            Message::SuggestPiece(index) => write!(f, "SuggestPiece(index={})", index),
+            Message::Reject(index, begin, len) =>
+                write!(f, "Reject(index={}, begin={}, len={})", index, begin, len),
+            Message::AllowedFast(index) => write!(f, "AllowedFast(index={})", index)
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum MessageError {
+    UnknownId(u8),
+    BadLength(u8, usize) // id and payload length
+}
+
+impl fmt::Display for MessageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::UnknownId(id) =>
+                write!(f, "Bad message ID: {}", id),
+            Self::BadLength(id, len) =>
+                write!(f, "Message {} has a payload of {} bytes, which is the wrong size", id, len)
+        }
+    }
+}
+impl Error for MessageError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // `Message` has no `PartialEq`, the `Debug` output shows every field
+    fn round_trip(message: Message) {
+        let expected = format!("{:?}", message);
+        let bytes = message.serialize();
+
+        assert_eq!(BigEndian::read_u32(&bytes[..4]) as usize, bytes.len() - 4);
+        assert_eq!(format!("{:?}", Message::try_from(&bytes[4..]).unwrap()), expected);
+    }
+
+    #[test]
+    fn every_variant_round_trips() {
+        round_trip(Message::KeepAlive);
+        round_trip(Message::Choke);
+        round_trip(Message::Unchoke);
+        round_trip(Message::Interested);
+        round_trip(Message::NotInterested);
+        round_trip(Message::Have(u32::MAX));
+        round_trip(Message::Bitfield(vec![0b1010_0000, 0xff]));
+        round_trip(Message::Request(1, 16384, 16384));
+        round_trip(Message::Piece(2, 32768, vec![1, 2, 3]));
+        round_trip(Message::Piece(2, 0, Vec::new()));
+        round_trip(Message::Cancel);
+        round_trip(Message::SuggestPiece(3));
+        round_trip(Message::Reject(4, 16384, 1000));
+        round_trip(Message::AllowedFast(5));
+    }
+
+    #[test]
+    fn unknown_ids_are_rejected() {
+        assert!(matches!(Message::try_from(&[20, 0, 0][..]), Err(MessageError::UnknownId(20))));
+    }
+
+    #[test]
+    fn payloads_of_the_wrong_size_are_rejected() {
+        assert!(matches!(Message::try_from(&[1, 0][..]), Err(MessageError::BadLength(1, 1))));
+        assert!(matches!(Message::try_from(&[4, 0, 0, 1][..]), Err(MessageError::BadLength(4, 3))));
+        assert!(matches!(Message::try_from(&[6, 0, 0, 0, 1][..]), Err(MessageError::BadLength(6, 4))));
+        assert!(matches!(Message::try_from(&[7, 0, 0, 0, 1, 0, 0][..]), Err(MessageError::BadLength(7, 6))));
+    }
+}
@@ -0,0 +1,107 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+use crate::connection::Connection;
+
+// Decides which piece a worker downloads next
+pub trait PieceStrategy: Send + Sync {
+    // `available` holds the queued pieces the peer of `conn` has, in queue order
+    fn select(&self, available: &[u32], conn: &Connection) -> Option<u32>;
+
+    // Called when a peer announces it has the piece, or with `false` when that peer goes away
+    fn update_availability(&self, _index: u32, _has: bool) {}
+}
+
+// Pieces in the order they were queued, retried pieces go to the back
+pub struct Fifo;
+
+// Lowest index first, so the start of the file is filled first (e.g. for streaming)
+pub struct Sequential;
+
+// Pieces fewer peers have first, so they don't disappear from the swarm
+pub struct RarestFirst {
+    availability: Mutex<Vec<u32>> // peers having each piece
+}
+
+#[derive(Clone, Copy)]
+pub enum PieceOrder {
+    Fifo,
+    Sequential,
+    RarestFirst
+}
+
+impl PieceStrategy for Fifo {
+    fn select(&self, available: &[u32], _conn: &Connection) -> Option<u32> {
+        available.first().copied()
+    }
+}
+
+impl PieceStrategy for Sequential {
+    fn select(&self, available: &[u32], _conn: &Connection) -> Option<u32> {
+        available.iter().min().copied()
+    }
+}
+
+impl RarestFirst {
+    pub fn new(piece_count: u32) -> RarestFirst {
+        RarestFirst {
+            availability: Mutex::new(vec![0; piece_count as usize])
+        }
+    }
+}
+
+impl PieceStrategy for RarestFirst {
+    // ties are broken by queue order
+    fn select(&self, available: &[u32], _conn: &Connection) -> Option<u32> {
+        let availability = self.availability.lock().unwrap();
+
+        available.iter()
+            .min_by_key(|&&index| availability[index as usize])
+            .copied()
+    }
+
+    fn update_availability(&self, index: u32, has: bool) {
+        let mut availability = self.availability.lock().unwrap();
+
+        if let Some(count) = availability.get_mut(index as usize) {
+            if has {
+                *count += 1;
+            } else {
+                *count = count.saturating_sub(1);
+            }
+        }
+    }
+}
+
+impl PieceOrder {
+    pub fn strategy(self, piece_count: u32) -> Box<dyn PieceStrategy> {
+        match self {
+            PieceOrder::Fifo => Box::new(Fifo),
+            PieceOrder::Sequential => Box::new(Sequential),
+            PieceOrder::RarestFirst => Box::new(RarestFirst::new(piece_count))
+        }
+    }
+}
+
+impl FromStr for PieceOrder {
+    type Err = UnknownPieceOrder;
+
+    fn from_str(s: &str) -> Result<PieceOrder, UnknownPieceOrder> {
+        match s {
+            "fifo" => Ok(PieceOrder::Fifo),
+            "sequential" => Ok(PieceOrder::Sequential),
+            "rarest" => Ok(PieceOrder::RarestFirst),
+            _ => Err(UnknownPieceOrder(s.to_string()))
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct UnknownPieceOrder(pub String);
+
+impl fmt::Display for UnknownPieceOrder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Unknown piece order {:?}, expected fifo, sequential or rarest", self.0)
+    }
+}
+impl std::error::Error for UnknownPieceOrder {}
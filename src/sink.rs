@@ -0,0 +1,200 @@
+use std::fs::File;
+use std::ops::Range;
+use std::sync::Mutex;
+use std::{io, mem};
+use crate::utils::{write_all_at, read_exact_at};
+
+// Where the downloaded pieces are stored, a file by default. Pieces never overlap,
+// so every worker writes its own at the piece offset. Reads are used to verify
+// the download and will be used for seeding.
+pub trait PieceSink: Send + Sync {
+    fn write_at(&self, data: &[u8], offset: u64) -> io::Result<()>;
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()>;
+
+    fn sync(&self) -> io::Result<()>;
+
+    // The downloaded data, for the sinks holding it in memory
+    fn take_contents(&self) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+// Written with positioned writes, so it needs no lock
+impl PieceSink for File {
+    fn write_at(&self, data: &[u8], offset: u64) -> io::Result<()> {
+        write_all_at(self, data, offset)
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        read_exact_at(self, buf, offset)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.sync_all()
+    }
+}
+
+// The files of a multi-file torrent, one after another in the torrent data.
+// Pieces and blocks can cross a file boundary, they are split between the files.
+pub struct FileSet {
+    files: Vec<(File, Range<u64>)> // with the range it takes in the torrent data
+}
+
+impl FileSet {
+    pub fn new(files: Vec<(File, Range<u64>)>) -> FileSet {
+        FileSet { files }
+    }
+
+    // Calls `f` with every file [offset, offset + len) touches, the offset to use in
+    // that file and the part of the buffer that goes there. Empty files are skipped.
+    fn for_each_part<F>(&self, offset: u64, len: usize, mut f: F) -> io::Result<()>
+        where F: FnMut(&File, u64, Range<usize>) -> io::Result<()>
+    {
+        let end = offset + len as u64;
+        let mut covered = offset;
+
+        for (file, range) in &self.files {
+            if range.end <= offset || range.start >= end {
+                continue;
+            }
+
+            let from = offset.max(range.start);
+            let to = end.min(range.end);
+
+            f(file, from - range.start, (from - offset) as usize..(to - offset) as usize)?;
+            covered = to;
+        }
+
+        if covered < end {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Range past the end of the download"));
+        }
+
+        Ok(())
+    }
+}
+
+impl PieceSink for FileSet {
+    fn write_at(&self, data: &[u8], offset: u64) -> io::Result<()> {
+        self.for_each_part(offset, data.len(), |file, file_offset, part| {
+            write_all_at(file, &data[part], file_offset)
+        })
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        self.for_each_part(offset, buf.len(), |file, file_offset, part| {
+            read_exact_at(file, &mut buf[part], file_offset)
+        })
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.files.iter().try_for_each(|(file, _)| file.sync_all())
+    }
+}
+
+// Holds the whole download in a buffer allocated up front
+pub struct MemorySink {
+    data: Mutex<Vec<u8>>
+}
+
+impl MemorySink {
+    pub fn new(length: usize) -> MemorySink {
+        MemorySink {
+            data: Mutex::new(vec![0; length])
+        }
+    }
+}
+
+impl PieceSink for MemorySink {
+    fn write_at(&self, data: &[u8], offset: u64) -> io::Result<()> {
+        let mut buf = self.data.lock().unwrap();
+        let begin = offset as usize;
+
+        match buf.get_mut(begin..begin + data.len()) {
+            Some(range) => {
+                range.copy_from_slice(data);
+
+                Ok(())
+            },
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, "Write past the end of the download"))
+        }
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let data = self.data.lock().unwrap();
+        let begin = offset as usize;
+
+        match data.get(begin..begin + buf.len()) {
+            Some(range) => {
+                buf.copy_from_slice(range);
+
+                Ok(())
+            },
+            None => Err(io::Error::from(io::ErrorKind::UnexpectedEof))
+        }
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn take_contents(&self) -> Option<Vec<u8>> {
+        Some(mem::take(&mut *self.data.lock().unwrap()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, OpenOptions};
+    use std::path::PathBuf;
+    use std::process;
+
+    // Files of 3, 0 and 5 bytes in a fresh directory, with their ranges
+    fn file_set(name: &str) -> (FileSet, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("bittorrent-client-{}-{}", name, process::id()));
+        let mut files = Vec::new();
+        let mut offset = 0;
+
+        fs::create_dir_all(&dir).unwrap();
+
+        for (i, &length) in [3u64, 0, 5].iter().enumerate() {
+            let file = OpenOptions::new().read(true).write(true).create(true).truncate(true)
+                .open(dir.join(i.to_string())).unwrap();
+
+            file.set_len(length).unwrap();
+            files.push((file, offset..offset + length));
+            offset += length;
+        }
+
+        (FileSet::new(files), dir)
+    }
+
+    #[test]
+    fn pieces_crossing_files_are_split() {
+        let (set, dir) = file_set("split");
+
+        set.write_at(&[1, 2, 3, 4], 1).unwrap();
+
+        let mut buf = [0; 8];
+
+        set.read_at(&mut buf, 0).unwrap();
+
+        assert_eq!(buf, [0, 1, 2, 3, 4, 0, 0, 0]);
+        assert_eq!(fs::read(dir.join("0")).unwrap(), [0, 1, 2]);
+        assert_eq!(fs::read(dir.join("1")).unwrap(), []);
+        assert_eq!(fs::read(dir.join("2")).unwrap(), [3, 4, 0, 0, 0]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn ranges_past_the_end_fail() {
+        let (set, dir) = file_set("past-end");
+
+        assert!(set.write_at(&[1, 2], 7).is_err());
+        assert!(set.read_at(&mut [0; 4], 6).is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
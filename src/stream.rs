@@ -0,0 +1,191 @@
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream, Ipv4Addr};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use log::{debug, warn};
+use crate::client::Client;
+use crate::torrent::{Torrent, FileInfo};
+
+// Serves the files of a torrent over HTTP while they download, so a media player
+// can play them with the sequential piece order. Only listens on localhost.
+// Range requests are answered piece by piece, waiting for the missing pieces.
+pub struct StreamServer {
+    listener: TcpListener,
+    files: Vec<FileInfo>,
+    piece_length: u64,
+    length: u64 // of the torrent data
+}
+
+// The byte range of a file a request asks for, `end` included as in HTTP
+struct Request {
+    head: bool, // the headers only
+    file: usize,
+    range: Option<(u64, u64)>
+}
+
+impl StreamServer {
+    const POLL_INTERVAL: Duration = Duration::from_millis(100);
+    const MAX_HEADERS: usize = 100;
+
+    pub fn bind(port: u16, torrent: &Torrent) -> io::Result<StreamServer> {
+        Ok(StreamServer {
+            listener: TcpListener::bind((Ipv4Addr::LOCALHOST, port))?,
+            files: torrent.files(),
+            piece_length: torrent.piece_length() as u64,
+            length: torrent.total_length()
+        })
+    }
+
+    // Answers every connection in its own thread, it never returns
+    pub fn run(self: Arc<Self>, client: Arc<Client>) {
+        for stream in self.listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("Error accepting stream connection: {}", e);
+                    continue;
+                }
+            };
+            let (server, client) = (self.clone(), client.clone());
+            let name = stream.peer_addr().map_or_else(|_| "stream".to_string(), |addr| format!("stream {}", addr));
+            let spawned = thread::Builder::new()
+                .name(name)
+                .spawn(move || {
+                    if let Err(e) = server.serve(stream, &client) {
+                        debug!("Stream connection ended: {}", e);
+                    }
+                });
+
+            if let Err(e) = spawned {
+                warn!("Error starting stream connection: {}", e);
+            }
+        }
+    }
+
+    // One request per connection, the file is `/` for a single-file torrent and
+    // `/<index>` for any of them, following the order of the files list
+    fn serve(&self, mut stream: TcpStream, client: &Client) -> io::Result<()> {
+        let request = match self.read_request(&stream)? {
+            Ok(request) => request,
+            Err(status) => return write!(stream, "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status)
+        };
+        let file = &self.files[request.file];
+        let (begin, end) = match request.range {
+            Some((begin, end)) if begin > end || begin >= file.length => {
+                return write!(stream, "HTTP/1.1 416 Range Not Satisfiable\r\nContent-Range: bytes */{}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", file.length);
+            },
+            Some((begin, end)) => {
+                write!(stream, "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\n", begin, end, file.length)?;
+                (begin, end + 1)
+            },
+            None => {
+                write!(stream, "HTTP/1.1 200 OK\r\n")?;
+                (0, file.length)
+            }
+        };
+
+        write!(stream, "Content-Type: application/octet-stream\r\nContent-Length: {}\r\nAccept-Ranges: bytes\r\nConnection: close\r\n\r\n", end - begin)?;
+
+        if request.head {
+            return Ok(());
+        }
+
+        // offsets in the torrent data from here on
+        let (mut offset, end) = (file.offset + begin, file.offset + end);
+
+        while offset < end {
+            let index = (offset / self.piece_length) as u32;
+            let piece_begin = index as u64 * self.piece_length;
+            let piece_length = self.piece_length.min(self.length - piece_begin);
+
+            self.wait_for_piece(client, index)?;
+
+            let piece = client.read_piece(piece_begin, piece_length as usize)?;
+            let from = (offset - piece_begin) as usize;
+            let to = (end - piece_begin).min(piece.len() as u64) as usize;
+
+            stream.write_all(&piece[from..to])?;
+            offset = piece_begin + to as u64;
+        }
+
+        Ok(())
+    }
+
+    // Blocks until the piece is downloaded, fails if it never will be
+    fn wait_for_piece(&self, client: &Client, index: u32) -> io::Result<()> {
+        if !client.torrent.is_wanted(index) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("Piece {} isn't selected for download", index)));
+        }
+
+        while !client.torrent.is_completed(index) {
+            if client.is_shutting_down() {
+                return Err(io::Error::new(io::ErrorKind::Interrupted, "Download stopped"));
+            }
+
+            thread::sleep(Self::POLL_INTERVAL);
+        }
+
+        Ok(())
+    }
+
+    // The request or the status to answer it with
+    fn read_request(&self, stream: &TcpStream) -> io::Result<Result<Request, &'static str>> {
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+
+        reader.read_line(&mut line)?;
+
+        let mut parts = line.split_whitespace();
+        let (method, path) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+        let mut range = None;
+
+        for _ in 0..Self::MAX_HEADERS {
+            let mut header = String::new();
+
+            if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+                break;
+            }
+
+            if let Some((name, value)) = header.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("range") {
+                    range = Some(value.trim().to_string());
+                }
+            }
+        }
+
+        let head = match method {
+            "GET" => false,
+            "HEAD" => true,
+            _ => return Ok(Err("405 Method Not Allowed"))
+        };
+        let file = match path.trim_start_matches('/') {
+            "" if self.files.len() == 1 => 0,
+            index => match index.parse::<usize>() {
+                Ok(index) if index < self.files.len() => index,
+                _ => return Ok(Err("404 Not Found"))
+            }
+        };
+        // a range that can't be parsed is ignored and the whole file is sent
+        let range = range.and_then(|range| parse_range(&range, self.files[file].length));
+
+        Ok(Ok(Request { head, file, range }))
+    }
+}
+
+// A single `bytes=begin-end` range, where either end can be left out. Returns
+// the first and last byte asked for, the last one at most the last of the file.
+fn parse_range(range: &str, length: u64) -> Option<(u64, u64)> {
+    let (begin, end) = range.strip_prefix("bytes=")?.split_once('-')?;
+    let last = length.saturating_sub(1);
+
+    match (begin.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix = suffix.parse::<u64>().ok()?.min(length);
+
+            (suffix > 0).then(|| (length - suffix, last))
+        },
+        (begin, "") => Some((begin.parse().ok()?, last)),
+        (begin, end) => Some((begin.parse().ok()?, end.parse::<u64>().ok()?.min(last)))
+    }
+}
@@ -1,12 +1,17 @@
 use std::collections::VecDeque;
 use std::error::Error;
 use std::{fs, fmt, io};
-use std::path::Path;
+use std::io::Read;
+use std::path::{Path, PathBuf};
 use std::convert::TryFrom;
+use std::time::Duration;
+use log::warn;
+use reqwest::header::CONTENT_TYPE;
 use serde::{Deserialize, Serialize};
-use serde_bencode;
 use serde_bytes::ByteBuf;
+use serde_bencode::value::Value;
 use sha1::{Digest, Sha1};
+use crate::utils::{is_safe_file_name, sanitize_path, to_hex};
 
 type PieceHash = Vec<u8>;
 
@@ -17,19 +22,33 @@ struct TorrentInfo {
     length: Option<u64>,
     #[serde(rename = "piece length")]
     piece_length: u32,
-    pieces: ByteBuf
+    pieces: ByteBuf,
+    private: Option<u8>, // 1 forbids getting peers from anything but the tracker (BEP 27)
+    md5sum: Option<String> // hex MD5 of the file, single-file torrents only
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 struct BencodeTorrent {
-    announce: String,
-    info: TorrentInfo
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    announce: String, // empty for trackerless torrents
+    info: TorrentInfo,
+    #[serde(rename = "url-list")]
+    url_list: Option<UrlList>
+}
+
+// Web seeds (BEP 19), given as a single URL or a list of them
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum UrlList {
+    One(String),
+    Many(Vec<String>)
 }
 
 #[derive(Deserialize, Serialize)]
 struct TorrentSubFile {
     path: Vec<String>,
-    length: u64
+    length: u64,
+    md5sum: Option<String>
 }
 
 #[derive(Deserialize)]
@@ -38,49 +57,166 @@ pub struct Torrent {
     pub info_hash: Vec<u8>,
     pub name: String,
     pub pieces: Vec<PieceHash>,
-    pub length: u64, // file size
+    length: u64, // size of all the files together
+    pub private: bool,
+    pub web_seeds: Vec<String>, // HTTP servers hosting the files
     files: Option<Vec<TorrentSubFile>>,
-    piece_length: u32
+    piece_length: u32,
+    md5sum: Option<String> // of the single file, multi-file torrents have one per file
 }
 
 #[derive(Clone)]
 pub struct Piece {
     pub index: u32,
     pub length: u32, // piece size
-    pub begin: u32,
+    pub begin: u64, // offset in the torrent, can go past 4 GiB
     hash: PieceHash,
-    end: u32
+    failed_peers: Vec<Vec<u8>> // peer_ids that sent data failing the hash
+}
+
+#[derive(Clone, Debug)]
+pub struct FileInfo {
+    pub path: PathBuf, // relative to the output directory, starting with the torrent name
+    pub length: u64,
+    pub offset: u64 // where the file starts in the torrent data
 }
 
 pub struct Block {
-    // index: u32,
+    pub index: u32, // index of the piece it belongs to
     pub begin: u32,
     pub end: u32,
     pub length: u32,
     pub data: Option<Vec<u8>>
 }
 
+impl FileInfo {
+    // Offset right past the file's last byte
+    pub fn end(&self) -> u64 {
+        self.offset + self.length
+    }
+}
+
 impl BencodeTorrent {
-    fn get_total_length(&self) -> u64 {
-        match self.info.length {
-            Some(length) => length,
-            None => self.info.files.as_ref()
-                .unwrap()
-                .iter()
-                .fold(0, |acc, file| acc + file.length)
+    // Single-file torrents give `length`, multi-file ones a `files` list
+    fn get_total_length(&self) -> Result<u64, OpenTorrentError> {
+        match (self.info.length, &self.info.files) {
+            (Some(length), _) => Ok(length),
+            (None, Some(files)) => files.iter()
+                .try_fold(0u64, |acc, file| acc.checked_add(file.length))
+                .ok_or_else(|| serde_bencode::Error::InvalidValue("Total length of the files overflows".to_string()).into()),
+            (None, None) => Err(OpenTorrentError::MissingInfoField("length"))
         }
     }
 }
 
 impl Torrent {
+    const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
     pub fn open<P: AsRef<Path>>(path: P) -> Result<Torrent, OpenTorrentError> {
-        let file = fs::read(path)?;
-        let bencode_torrent = serde_bencode::from_bytes::<BencodeTorrent>(&file)?;
+        let path = path.as_ref();
+        let file = fs::read(path).map_err(|e| OpenTorrentError::reading(path, e))?;
+
+        Self::from_bytes(&file)
+    }
+
+    pub fn from_reader<R: Read>(mut reader: R) -> Result<Torrent, OpenTorrentError> {
+        let mut buf = Vec::new();
+
+        reader.read_to_end(&mut buf)?;
+
+        Self::from_bytes(&buf)
+    }
+
+    // Downloads the .torrent file from an http(s) URL
+    pub fn fetch(url: &str) -> Result<Torrent, OpenTorrentError> {
+        let mut buf = Vec::new();
+        let mut res = reqwest::blocking::Client::builder()
+            .timeout(Self::FETCH_TIMEOUT)
+            .build()?
+            .get(url)
+            .send()?;
+
+        if !res.status().is_success() {
+            return Err(OpenTorrentError::BadResponse(format!("Server answered with {}", res.status())));
+        }
+
+        let is_html = res.headers()
+            .get(CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .is_some_and(|content_type| content_type.starts_with("text/html"));
+
+        if is_html {
+            return Err(OpenTorrentError::BadResponse("Server returned a web page instead of a torrent".to_string()));
+        }
+
+        res.copy_to(&mut buf)?;
+
+        Self::from_bytes(&buf)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Torrent, OpenTorrentError> {
+        let bencode_torrent = serde_bencode::from_bytes::<BencodeTorrent>(bytes)
+            .map_err(|e| OpenTorrentError::parsing(bytes, e))?;
         let torrent = Torrent::try_from(bencode_torrent)?;
 
+        if torrent.announce.is_empty() {
+            warn!("Torrent {} has no announce URL, its peers have to come from elsewhere", torrent.name);
+        }
+
         Ok(torrent)
     }
 
+    // Builds the .torrent file sharing a file or a directory, returned bencoded.
+    // A directory's files are added recursively in path order.
+    pub fn create_from_path<P: AsRef<Path>>(path: P, piece_length: u32, announce: &str) -> Result<Vec<u8>, CreateTorrentError> {
+        let path = path.as_ref();
+        let name = path.file_name()
+            .and_then(|name| name.to_str())
+            .filter(|name| is_safe_file_name(name))
+            .ok_or_else(|| CreateTorrentError::BadInput(format!("{} has no usable file name", path.display())))?;
+
+        if piece_length == 0 {
+            return Err(CreateTorrentError::BadInput("Piece length can't be 0".to_string()));
+        }
+
+        let (files, length) = if path.is_dir() {
+            let mut files = Vec::new();
+
+            collect_files(path, &mut Vec::new(), &mut files)?;
+
+            let length = files.iter().map(|(_, file)| file.length).sum::<u64>();
+
+            (Some(files), length)
+        } else {
+            (None, fs::metadata(path)?.len())
+        };
+
+        if length == 0 {
+            return Err(CreateTorrentError::BadInput(format!("{} has no data to share", path.display())));
+        }
+
+        let paths = match &files {
+            Some(files) => files.iter().map(|(path, _)| path.clone()).collect(),
+            None => vec![path.to_path_buf()]
+        };
+        let info = TorrentInfo {
+            name: name.to_string(),
+            length: if files.is_none() { Some(length) } else { None },
+            files: files.map(|files| files.into_iter().map(|(_, file)| file).collect()),
+            piece_length,
+            pieces: ByteBuf::from(hash_pieces(&paths, piece_length)?),
+            private: None,
+            md5sum: None
+        };
+        let torrent = BencodeTorrent {
+            announce: announce.to_string(),
+            info,
+            url_list: None
+        };
+
+        Ok(serde_bencode::to_bytes(&torrent)?)
+    }
+
     pub fn create_piece_queue(&self) -> VecDeque<Piece> {
         let mut piece_queue = VecDeque::<Piece>::new();
         let piece_length = self.piece_length as u64;
@@ -88,12 +224,12 @@ impl Torrent {
 
         for (index, hash) in self.pieces.iter().enumerate() {
             // if is last piece and last piece is smaller than piece_length
-            if index == self.pieces.len() - 1 && self.length % piece_length > 0 {
+            if index == self.pieces.len() - 1 && !self.length.is_multiple_of(piece_length) {
                 length = self.length % piece_length;
             }
 
             let piece = Piece::new(index as u32,
-                                   (index * piece_length as usize) as u32,
+                                   index as u64 * piece_length,
                                    length as u32,
                                    hash.to_owned());
 
@@ -102,15 +238,108 @@ impl Torrent {
 
         piece_queue
     }
+
+    // 40 characters hex string, as shown by other clients and in magnet links
+    pub fn info_hash_hex(&self) -> String {
+        to_hex(&self.info_hash)
+    }
+
+    pub fn piece_count(&self) -> u32 {
+        self.pieces.len() as u32
+    }
+
+    // Every piece but the last one is this long
+    pub fn piece_length(&self) -> u32 {
+        self.piece_length
+    }
+
+    pub fn total_length(&self) -> u64 {
+        self.length
+    }
+
+    // Multi-file torrents are saved in a directory named after the torrent, even with a single file
+    pub fn is_multi_file(&self) -> bool {
+        self.files.is_some()
+    }
+
+    pub fn file_count(&self) -> usize {
+        self.files.as_ref().map_or(1, |files| files.len())
+    }
+
+    // Marks, for every piece, whether it holds data of any of the given files.
+    // Pieces straddling a selected and an unselected file are wanted.
+    pub fn wanted_pieces(&self, file_indices: &[usize]) -> Vec<bool> {
+        let files = self.files();
+        let selected = file_indices.iter()
+            .filter_map(|&index| files.get(index))
+            .collect::<Vec<_>>();
+
+        (0..self.pieces.len() as u32)
+            .map(|index| {
+                let (begin, end) = self.piece_range(index);
+
+                selected.iter().any(|file| begin < file.end() && file.offset < end)
+            })
+            .collect()
+    }
+
+    // Byte range [begin, end) of a piece in the torrent data, the last one can be shorter
+    pub fn piece_range(&self, index: u32) -> (u64, u64) {
+        let begin = index as u64 * self.piece_length as u64;
+
+        (begin, (begin + self.piece_length as u64).min(self.length))
+    }
+
+    // Every file with its path starting with the torrent name and the offset it
+    // starts at in the torrent data. A single-file torrent's only path is its name.
+    pub fn files(&self) -> Vec<FileInfo> {
+        match &self.files {
+            Some(files) => {
+                let mut offset = 0;
+
+                files.iter()
+                    .map(|file| {
+                        let path = sanitize_path(&self.name, &file.path)
+                            .expect("File paths are checked when the torrent is opened");
+                        let info = FileInfo { path, length: file.length, offset };
+
+                        offset += file.length;
+                        info
+                    })
+                    .collect()
+            },
+            None => vec![FileInfo { path: PathBuf::from(&self.name), length: self.length, offset: 0 }]
+        }
+    }
+
+    // The md5sum of every file in `files` order, for the torrents that carry them
+    pub fn file_md5sums(&self) -> Vec<Option<String>> {
+        match &self.files {
+            Some(files) => files.iter().map(|file| file.md5sum.clone()).collect(),
+            None => vec![self.md5sum.clone()]
+        }
+    }
 }
 
 impl TryFrom<BencodeTorrent> for Torrent {
-    type Error = serde_bencode::Error;
+    type Error = OpenTorrentError;
 
     fn try_from(bencode: BencodeTorrent) -> Result<Torrent, Self::Error> {
         let info_bytes = serde_bencode::to_bytes(&bencode.info)?;
-        let length = bencode.info.length
-            .unwrap_or_else(|| bencode.get_total_length());
+        let length = bencode.get_total_length()?;
+
+        validate_pieces(length, bencode.info.piece_length, bencode.info.pieces.len())?;
+        validate_paths(&bencode.info)?;
+
+        // some torrents carry an empty `url-list`
+        let web_seeds = match bencode.url_list {
+            Some(UrlList::One(url)) => vec![url],
+            Some(UrlList::Many(urls)) => urls,
+            None => Vec::new()
+        };
+        let web_seeds = web_seeds.into_iter()
+            .filter(|url| !url.is_empty())
+            .collect();
 
         Ok(Torrent {
             info_hash: Sha1::digest(&info_bytes).to_vec(),
@@ -118,7 +347,10 @@ impl TryFrom<BencodeTorrent> for Torrent {
             announce: bencode.announce,
             files: bencode.info.files,
             length,
+            private: bencode.info.private == Some(1),
+            web_seeds,
             piece_length: bencode.info.piece_length,
+            md5sum: bencode.info.md5sum,
             pieces: bencode.info.pieces.chunks(20)
                 .map(|s| s.to_vec())
                 .collect()
@@ -126,33 +358,144 @@ impl TryFrom<BencodeTorrent> for Torrent {
     }
 }
 
-impl Piece {
-    const MAX_BLOCK_SIZE: u32 = 16384;
+// Piece indexes are u32 on the wire and offsets are computed as `index * piece_length`,
+// so the hashes must describe exactly the pieces needed to cover `length`.
+fn validate_pieces(length: u64, piece_length: u32, pieces_bytes: usize) -> Result<(), serde_bencode::Error> {
+    if piece_length == 0 {
+        return Err(serde_bencode::Error::InvalidValue("Piece length can't be 0".to_string()));
+    }
+
+    if !pieces_bytes.is_multiple_of(20) {
+        return Err(serde_bencode::Error::InvalidLength(
+            format!("Pieces length {} is not a multiple of 20", pieces_bytes)));
+    }
+
+    let expected_pieces = length.div_ceil(piece_length as u64);
+
+    if expected_pieces > u32::MAX as u64 {
+        return Err(serde_bencode::Error::InvalidLength(
+            format!("Torrent has {} pieces, more than can be addressed", expected_pieces)));
+    }
+
+    if expected_pieces != (pieces_bytes / 20) as u64 {
+        return Err(serde_bencode::Error::InvalidLength(
+            format!("Expected {} piece hashes but got {}", expected_pieces, pieces_bytes / 20)));
+    }
+
+    Ok(())
+}
+
+// Adds the files under `dir` to `files` sorted by path, along with their path
+// relative to the torrent directory. `prefix` holds the path of `dir` itself.
+fn collect_files(dir: &Path, prefix: &mut Vec<String>, files: &mut Vec<(PathBuf, TorrentSubFile)>) -> Result<(), CreateTorrentError> {
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<fs::DirEntry>>>()?;
+
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let name = entry.file_name()
+            .into_string()
+            .map_err(|name| CreateTorrentError::BadInput(format!("File name {:?} isn't valid UTF-8", name)))?;
+
+        prefix.push(name);
+
+        if entry.path().is_dir() {
+            collect_files(&entry.path(), prefix, files)?;
+        } else {
+            let file = TorrentSubFile {
+                path: prefix.clone(),
+                length: entry.metadata()?.len(),
+                md5sum: None
+            };
+
+            files.push((entry.path(), file));
+        }
+
+        prefix.pop();
+    }
+
+    Ok(())
+}
+
+// SHA-1 of every piece of the files read one after another, the last piece can be shorter
+fn hash_pieces(paths: &[PathBuf], piece_length: u32) -> io::Result<Vec<u8>> {
+    let mut hashes = Vec::new();
+    let mut piece = Vec::with_capacity(piece_length as usize);
+
+    for path in paths {
+        let mut file = fs::File::open(path)?;
+
+        loop {
+            let filled = piece.len();
+
+            (&mut file).take((piece_length as usize - filled) as u64).read_to_end(&mut piece)?;
+
+            if piece.len() == filled {
+                break;
+            }
+
+            if piece.len() == piece_length as usize {
+                hashes.extend(Sha1::digest(&piece));
+                piece.clear();
+            }
+        }
+    }
+
+    if !piece.is_empty() {
+        hashes.extend(Sha1::digest(&piece));
+    }
+
+    Ok(hashes)
+}
 
-    fn new(index: u32, begin: u32, length: u32, hash: PieceHash) -> Self {
+// File names come from an untrusted torrent, none of them may escape the output directory
+fn validate_paths(info: &TorrentInfo) -> Result<(), serde_bencode::Error> {
+    if !is_safe_file_name(&info.name) {
+        return Err(serde_bencode::Error::InvalidValue(format!("Unsafe torrent name: {:?}", info.name)));
+    }
+
+    for file in info.files.iter().flatten() {
+        if let Err(e) = sanitize_path(&info.name, &file.path) {
+            return Err(serde_bencode::Error::InvalidValue(e.to_string()));
+        }
+    }
+
+    Ok(())
+}
+
+impl Piece {
+    fn new(index: u32, begin: u64, length: u32, hash: PieceHash) -> Self {
         Piece {
             index,
             hash,
             length,
             begin,
-            end: begin + length
+            failed_peers: Vec::new()
+        }
+    }
+
+    // Remembers a peer that sent corrupt data for this piece, so others are preferred
+    pub fn add_failed_peer(&mut self, peer_id: &[u8]) {
+        if !self.has_failed(peer_id) {
+            self.failed_peers.push(peer_id.to_vec());
         }
     }
 
-    pub fn create_block_queue(&self) -> Vec<Block> {
+    pub fn has_failed(&self, peer_id: &[u8]) -> bool {
+        self.failed_peers.iter().any(|failed| failed == peer_id)
+    }
+
+    pub fn create_block_queue(&self, block_size: u32) -> Vec<Block> {
         let mut block_queue = Vec::<Block>::new();
-        let mut block_length = Self::MAX_BLOCK_SIZE;
-        let num_of_blocks = (self.length as f32 / Self::MAX_BLOCK_SIZE as f32).ceil() as u32;
+        // integer math, an f32 rounds lengths above 16 MiB and can lose the last block
+        let num_of_blocks = self.length.div_ceil(block_size);
 
         for i in 0..num_of_blocks {
-            // if is last block and last block is smaller than block_length
-            if i == num_of_blocks - 1 && self.length % block_length > 0 {
-                block_length = self.length % block_length;
-            }
-
-            let begin = i * Self::MAX_BLOCK_SIZE;
+            let begin = i * block_size;
+            // only the last block can be shorter
+            let block_length = block_size.min(self.length - begin);
             let end = begin + block_length;
-            let block = Block::new(/*i,*/ begin, end, block_length);
+            let block = Block::new(self.index, begin, end, block_length);
 
             block_queue.push(block);
         }
@@ -160,19 +503,24 @@ impl Piece {
         block_queue
     }
 
-    pub fn check_integrity(&self, hash: PieceHash) -> Result<(), IntegrityError> {
+    // Hashes the piece data and checks it against the torrent
+    pub fn verify(&self, data: &[u8]) -> Result<(), IntegrityError> {
+        self.check_integrity(Sha1::digest(data).to_vec())
+    }
+
+    fn check_integrity(&self, hash: PieceHash) -> Result<(), IntegrityError> {
         if self.hash.eq(&hash) {
             Ok(())
         } else {
-            Err(IntegrityError(&self.hash, hash))
+            Err(IntegrityError(self.hash.clone(), hash))
         }
     }
 }
 
 impl Block {
-    pub fn new(/*index: u32,*/ begin: u32, end: u32, length: u32) -> Block {
+    pub fn new(index: u32, begin: u32, end: u32, length: u32) -> Block {
         Block {
-            // index,
+            index,
             begin,
             end,
             length,
@@ -181,13 +529,20 @@ impl Block {
     }
 }
 
+// Blocks are the same request when they cover the same bytes of the same piece, whatever their data
+impl PartialEq for Block {
+    fn eq(&self, other: &Self) -> bool {
+        self.index == other.index && self.begin == other.begin && self.length == other.length
+    }
+}
+
 impl fmt::Display for Torrent {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let mut files_names = Vec::new();
 
         match &self.files {
             Some(files) => {
-                files_names = files.into_iter()
+                files_names = files.iter()
                     .map(|f| &f.path[&f.path.len() - 1])
                     .collect::<Vec<&String>>();
             },
@@ -196,11 +551,13 @@ impl fmt::Display for Torrent {
 
         write!(f, "Torrent:\n\
                    ----Name: {}\n\
+                   ----Info hash: {}\n\
                    ----Files: {:?}\n\
                    ----Size: {}\n\
                    ----Number of pieces: {}\n\
                    ----Size of pieces: {}",
                self.name,
+               self.info_hash_hex(),
                files_names,
                self.length,
                self.pieces.len(),
@@ -210,28 +567,108 @@ impl fmt::Display for Torrent {
 }
 
 #[derive(Debug)]
-pub struct IntegrityError<'a>(&'a PieceHash, PieceHash);
+pub struct IntegrityError(PieceHash, PieceHash);
 
-impl<'a> fmt::Display for IntegrityError<'a> {
+impl fmt::Display for IntegrityError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Incorrect piece hash, Expected hash: {:?} but got {:?}", self.0, self.1)
+        write!(f, "Incorrect piece hash, Expected hash: {} but got {}", to_hex(&self.0), to_hex(&self.1))
     }
 }
-impl<'a> Error for IntegrityError<'a> {}
+impl Error for IntegrityError {}
 
 #[derive(Debug)]
-pub enum OpenTorrentError {
+pub enum CreateTorrentError {
+    IOError(io::Error),
     SerializationError(serde_bencode::Error),
-    IOError(io::Error)
+    BadInput(String)
 }
 
-impl fmt::Display for OpenTorrentError {
+impl fmt::Display for CreateTorrentError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
+            Self::IOError(e) =>
+                write!(f, "Error reading files: {}", e),
             Self::SerializationError(e) =>
                 write!(f, "{}", e),
-            Self::IOError(..) =>
-                write!(f, "Error reading file")
+            Self::BadInput(reason) =>
+                write!(f, "Can't create torrent: {}", reason)
+        }
+    }
+}
+impl From<io::Error> for CreateTorrentError {
+    fn from(err: io::Error) -> Self {
+        Self::IOError(err)
+    }
+}
+impl From<serde_bencode::Error> for CreateTorrentError {
+    fn from(err: serde_bencode::Error) -> Self {
+        Self::SerializationError(err)
+    }
+}
+impl Error for CreateTorrentError {}
+
+#[derive(Debug)]
+pub enum OpenTorrentError {
+    FileNotFound(PathBuf),
+    PermissionDenied(PathBuf),
+    IOError(io::Error),
+    InvalidBencode(serde_bencode::Error),
+    MissingInfoDict,
+    MissingInfoField(&'static str),
+    SerializationError(serde_bencode::Error), // valid bencode, but not a usable torrent
+    RequestError(reqwest::Error),
+    BadResponse(String)
+}
+
+impl OpenTorrentError {
+    const REQUIRED_INFO_FIELDS: [&'static str; 3] = ["name", "piece length", "pieces"];
+
+    // Names the file for the errors a wrong path or permissions cause
+    fn reading(path: &Path, err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::NotFound => Self::FileNotFound(path.to_path_buf()),
+            io::ErrorKind::PermissionDenied => Self::PermissionDenied(path.to_path_buf()),
+            _ => Self::IOError(err)
+        }
+    }
+
+    // Tells broken bencode apart from a dictionary missing what every torrent needs
+    fn parsing(bytes: &[u8], err: serde_bencode::Error) -> Self {
+        match serde_bencode::from_bytes::<Value>(bytes) {
+            Err(_) => Self::InvalidBencode(err),
+            Ok(Value::Dict(dict)) => match dict.get(&b"info"[..]) {
+                None => Self::MissingInfoDict,
+                Some(Value::Dict(info)) => Self::REQUIRED_INFO_FIELDS.iter()
+                    .find(|field| !info.contains_key(field.as_bytes()))
+                    .map_or(Self::SerializationError(err), |field| Self::MissingInfoField(field)),
+                Some(_) => Self::SerializationError(err)
+            },
+            Ok(_) => Self::SerializationError(err)
+        }
+    }
+}
+
+impl fmt::Display for OpenTorrentError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::FileNotFound(path) =>
+                write!(f, "Torrent file {} not found", path.display()),
+            Self::PermissionDenied(path) =>
+                write!(f, "Not allowed to read torrent file {}", path.display()),
+            Self::IOError(e) =>
+                write!(f, "Error reading file: {}", e),
+            Self::InvalidBencode(e) =>
+                write!(f, "Torrent is not valid bencode: {}", e),
+            Self::MissingInfoDict =>
+                write!(f, "Torrent has no info dictionary"),
+            Self::MissingInfoField(field) =>
+                write!(f, "Torrent info dict missing '{}'", field),
+            Self::SerializationError(e) =>
+                write!(f, "Invalid torrent: {}", e),
+            Self::RequestError(e) =>
+                write!(f, "Error downloading torrent: {}", e),
+            Self::BadResponse(msg) =>
+                write!(f, "Error downloading torrent: {}", msg)
         }
     }
 }
@@ -245,3 +682,181 @@ impl From<io::Error> for OpenTorrentError {
         Self::IOError(err)
     }
 }
+impl From<reqwest::Error> for OpenTorrentError {
+    fn from(err: reqwest::Error) -> Self {
+        Self::RequestError(err)
+    }
+}
+impl Error for OpenTorrentError {}
+
+// Also used by the other modules' tests
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    const GIB: u64 = 1 << 30;
+
+    // A single-file torrent with zeroed piece hashes
+    pub(crate) fn torrent(length: u64, piece_length: u32) -> Torrent {
+        let pieces = length.div_ceil(piece_length as u64) as usize * 20;
+        let mut bytes = format!("d8:announce31:http://tracker.example/announce4:infod6:lengthi{}e4:name4:file12:piece lengthi{}e6:pieces{}:",
+                                length, piece_length, pieces).into_bytes();
+
+        bytes.extend(vec![0; pieces]);
+        bytes.extend(b"ee");
+
+        Torrent::from_bytes(&bytes).unwrap()
+    }
+
+    #[test]
+    fn last_piece_of_a_torrent_over_4_gib() {
+        let torrent = torrent(5 * GIB + 1000, 1 << 20);
+        let last = torrent.create_piece_queue().pop_back().unwrap();
+
+        assert_eq!(torrent.piece_count(), 5 * 1024 + 1);
+        assert_eq!(last.index, 5 * 1024);
+        assert_eq!(last.begin, 5 * GIB);
+        assert!(last.begin > u32::MAX as u64);
+        assert_eq!(last.length, 1000);
+        assert_eq!(torrent.piece_range(last.index), (5 * GIB, 5 * GIB + 1000));
+    }
+
+    // Blocks cover the piece exactly, one after the other, only the last one can be shorter
+    fn assert_blocks_cover(piece: &Piece, block_size: u32) {
+        let blocks = piece.create_block_queue(block_size);
+        let mut begin = 0;
+
+        for block in &blocks {
+            assert_eq!(block.index, piece.index);
+            assert_eq!(block.begin, begin);
+            assert_eq!(block.end, block.begin + block.length);
+            assert!(block.length > 0 && block.length <= block_size);
+            begin = block.end;
+        }
+
+        assert_eq!(begin, piece.length);
+    }
+
+    #[test]
+    fn pieces_that_are_not_a_power_of_two() {
+        let torrent = torrent(2 * 100_000 + 5000, 100_000);
+        let pieces = torrent.create_piece_queue();
+
+        assert_eq!(pieces.iter().map(|piece| piece.length).collect::<Vec<_>>(), vec![100_000, 100_000, 5000]);
+        assert_eq!(pieces[2].begin, 200_000);
+
+        for piece in &pieces {
+            assert_blocks_cover(piece, 16 * 1024);
+        }
+    }
+
+    #[test]
+    fn piece_smaller_than_one_block() {
+        let piece = Piece::new(0, 0, 1000, vec![0; 20]);
+        let blocks = piece.create_block_queue(16 * 1024);
+
+        assert_eq!(blocks.len(), 1);
+        assert_eq!((blocks[0].begin, blocks[0].end, blocks[0].length), (0, 1000, 1000));
+    }
+
+    #[test]
+    fn last_block_is_shorter() {
+        let piece = Piece::new(0, 0, 40_000, vec![0; 20]);
+        let lengths = piece.create_block_queue(16 * 1024).iter().map(|block| block.length).collect::<Vec<_>>();
+
+        assert_eq!(lengths, vec![16384, 16384, 7232]);
+        assert_blocks_cover(&piece, 16 * 1024);
+    }
+
+    #[test]
+    fn verify_hashes_the_data() {
+        let data = b"piece data";
+        let piece = Piece::new(0, 0, data.len() as u32, Sha1::digest(data).to_vec());
+
+        assert!(piece.verify(data).is_ok());
+        assert!(piece.verify(b"piece dat\0").is_err());
+    }
+
+    #[test]
+    fn created_torrent_reopens_with_the_same_info_hash() {
+        let dir = std::env::temp_dir().join(format!("bittorrent-client-create-{}", std::process::id()));
+        let shared = dir.join("shared");
+        let (first, second) = (vec![1; 50_000], vec![2; 30_000]);
+
+        fs::create_dir_all(shared.join("sub")).unwrap();
+        fs::write(shared.join("a.bin"), &first).unwrap();
+        fs::write(shared.join("sub").join("b.bin"), &second).unwrap();
+
+        let bytes = Torrent::create_from_path(&shared, 32 * 1024, "http://tracker.example/announce").unwrap();
+
+        fs::write(dir.join("shared.torrent"), &bytes).unwrap();
+
+        let opened = Torrent::open(dir.join("shared.torrent")).unwrap();
+
+        assert_eq!(opened.info_hash, Torrent::from_bytes(&bytes).unwrap().info_hash);
+        // the info dict is the last key of the created torrent, hashed as written
+        let info_start = bytes.windows(6).position(|window| window == b"4:info").unwrap() + 6;
+
+        assert_eq!(opened.info_hash, Sha1::digest(&bytes[info_start..bytes.len() - 1]).to_vec());
+        assert_eq!(opened.name, "shared");
+        assert_eq!(opened.total_length(), 80_000);
+        assert_eq!(opened.files().iter().map(|file| file.path.clone()).collect::<Vec<_>>(),
+                   vec![Path::new("shared").join("a.bin"), Path::new("shared").join("sub").join("b.bin")]);
+
+        let data = [first, second].concat();
+
+        for piece in opened.create_piece_queue() {
+            let begin = piece.begin as usize;
+
+            assert!(piece.verify(&data[begin..begin + piece.length as usize]).is_ok());
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn info_hash_renders_as_hex() {
+        let mut torrent = torrent(10, 16384);
+
+        torrent.info_hash = Sha1::digest(b"abc").to_vec();
+
+        assert_eq!(torrent.info_hash_hex(), "a9993e364706816aba3e25717850c26c9cd0d89d");
+    }
+
+    #[test]
+    fn integrity_error_shows_hex_hashes() {
+        let piece = Piece::new(0, 0, 3, Sha1::digest(b"abc").to_vec());
+        let error = piece.verify(b"abd").unwrap_err().to_string();
+
+        assert!(error.contains("a9993e364706816aba3e25717850c26c9cd0d89d"), "{}", error);
+        assert!(error.contains(&to_hex(&Sha1::digest(b"abd"))), "{}", error);
+        assert!(!error.contains('['), "{}", error);
+    }
+
+    // Two pieces, the second one shorter
+    const SAMPLE_TORRENT: &[u8] = b"d8:announce31:http://tracker.example/announce4:infod6:lengthi20000e4:name10:sample.iso\
+        12:piece lengthi16384e6:pieces40:aaaaaaaaaaaaaaaaaaaabbbbbbbbbbbbbbbbbbbbee";
+
+    #[test]
+    fn sample_torrent_is_decoded() {
+        let torrent = Torrent::from_bytes(SAMPLE_TORRENT).unwrap();
+
+        assert_eq!(torrent.announce, "http://tracker.example/announce");
+        assert_eq!(torrent.name, "sample.iso");
+        assert_eq!(torrent.total_length(), 20000);
+        assert_eq!(torrent.piece_length(), 16384);
+        assert_eq!(torrent.piece_count(), 2);
+        assert_eq!(torrent.pieces, vec![vec![b'a'; 20], vec![b'b'; 20]]);
+        assert_eq!(torrent.info_hash_hex(), "fd36cb6690465fa1469cf3fb17f7458c5c7c308f");
+        assert!(!torrent.is_multi_file());
+    }
+
+    #[test]
+    fn malformed_bytes_are_invalid_bencode() {
+        let truncated = &SAMPLE_TORRENT[..SAMPLE_TORRENT.len() - 30];
+
+        for bytes in [&b""[..], b"not bencode", truncated] {
+            assert!(matches!(Torrent::from_bytes(bytes), Err(OpenTorrentError::InvalidBencode(_))));
+        }
+    }
+}
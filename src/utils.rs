@@ -1,16 +1,198 @@
+use std::path::{Path, PathBuf, Component};
+use std::error::Error;
+use std::fs::File;
+use std::time::Duration;
+use std::{fmt, io};
 use percent_encoding::percent_encode_byte;
 
-#[macro_export]
-macro_rules! println_thread {
-    ($($arg:tt)*) => {
-        let msg = format!($($arg)*);
+pub fn url_encode(bytes: &[u8]) -> String {
+    bytes.iter()
+        .map(|b| percent_encode_byte(*b))
+        .collect::<String>()
+}
+
+// Lowercase hex, the usual way to show hashes
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// Formats as hh:mm:ss, "--:--:--" when unknown
+pub fn format_eta(eta: Option<Duration>) -> String {
+    match eta {
+        Some(eta) => {
+            let secs = eta.as_secs();
 
-        println!("Thread [{}]: {}", std::thread::current().name().unwrap(), msg);
+            format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+        },
+        None => "--:--:--".to_string()
     }
 }
 
-pub fn url_encode(bytes: &Vec<u8>) -> String {
-    bytes.into_iter()
-        .map(|b| percent_encode_byte(*b))
-        .collect::<String>()
+// Writes the whole buffer at `offset` with a positioned write, so there is no
+// separate seek and the file cursor isn't shared between writers
+#[cfg(unix)]
+pub fn write_all_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+
+    file.write_all_at(buf, offset)
+}
+
+#[cfg(windows)]
+pub fn write_all_at(file: &File, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+
+    while !buf.is_empty() {
+        match file.seek_write(buf, offset) {
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write whole buffer")),
+            Ok(n) => {
+                buf = &buf[n..];
+                offset += n as u64;
+            },
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e)
+        }
+    }
+
+    Ok(())
+}
+
+// Reads exactly `buf.len()` bytes at `offset` with a positioned read, like `write_all_at`
+#[cfg(unix)]
+pub fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+
+    file.read_exact_at(buf, offset)
+}
+
+#[cfg(windows)]
+pub fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset) {
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "failed to fill whole buffer")),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            },
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e)
+        }
+    }
+
+    Ok(())
+}
+
+// A file name is safe when it's a single plain path component, so it can't
+// point outside the directory it is joined to
+pub fn is_safe_file_name(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+
+    matches!((components.next(), components.next()), (Some(Component::Normal(_)), None))
+}
+
+// Joins a file path coming from a torrent onto `base`. Empty and `.` components
+// are skipped, while `..`, roots and drive prefixes are rejected since they
+// could make a malicious torrent write outside `base`.
+pub fn sanitize_path<P: AsRef<Path>>(base: P, path: &[String]) -> Result<PathBuf, UnsafePathError> {
+    let mut result = base.as_ref().to_path_buf();
+    let mut pushed = false;
+
+    for component in path.iter().flat_map(|part| Path::new(part).components()) {
+        match component {
+            Component::Normal(part) => {
+                result.push(part);
+                pushed = true;
+            },
+            Component::CurDir => {},
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) =>
+                return Err(UnsafePathError(path.join("/")))
+        }
+    }
+
+    if pushed {
+        Ok(result)
+    } else {
+        Err(UnsafePathError(path.join("/")))
+    }
+}
+
+#[derive(Debug)]
+pub struct UnsafePathError(pub String);
+
+impl fmt::Display for UnsafePathError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Unsafe file path in torrent: {:?}", self.0)
+    }
+}
+impl Error for UnsafePathError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(parts: &[&str]) -> Vec<String> {
+        parts.iter().map(|part| part.to_string()).collect()
+    }
+
+    #[test]
+    fn plain_paths_are_joined() {
+        let joined = sanitize_path("out", &path(&["dir", "file.txt"])).unwrap();
+
+        assert_eq!(joined, Path::new("out").join("dir").join("file.txt"));
+    }
+
+    #[test]
+    fn empty_and_current_components_are_skipped() {
+        let joined = sanitize_path("out", &path(&["", "dir", ".", "file.txt"])).unwrap();
+
+        assert_eq!(joined, Path::new("out").join("dir").join("file.txt"));
+    }
+
+    #[test]
+    fn parent_components_are_rejected() {
+        assert!(sanitize_path("out", &path(&["..", "..", "etc", "passwd"])).is_err());
+        assert!(sanitize_path("out", &path(&["dir", "..", "..", "file.txt"])).is_err());
+        assert!(sanitize_path("out", &path(&["dir/../../file.txt"])).is_err());
+    }
+
+    #[test]
+    fn absolute_paths_are_rejected() {
+        assert!(sanitize_path("out", &path(&["/etc", "passwd"])).is_err());
+        assert!(sanitize_path("out", &path(&["dir", "/etc/passwd"])).is_err());
+    }
+
+    #[test]
+    fn paths_without_a_name_are_rejected() {
+        assert!(sanitize_path("out", &path(&[])).is_err());
+        assert!(sanitize_path("out", &path(&["", "."])).is_err());
+    }
+
+    #[test]
+    fn file_names_are_single_components() {
+        assert!(is_safe_file_name("file.iso"));
+        assert!(!is_safe_file_name("dir/file.iso"));
+        assert!(!is_safe_file_name(".."));
+        assert!(!is_safe_file_name("/file.iso"));
+        assert!(!is_safe_file_name(""));
+    }
+
+    #[test]
+    fn eta_formatting() {
+        assert_eq!(format_eta(None), "--:--:--");
+        assert_eq!(format_eta(Some(Duration::ZERO)), "00:00:00");
+        assert_eq!(format_eta(Some(Duration::from_secs(59))), "00:00:59");
+        assert_eq!(format_eta(Some(Duration::from_secs(45296))), "12:34:56");
+        assert_eq!(format_eta(Some(Duration::from_secs(100 * 3600 + 1))), "100:00:01");
+    }
+
+    #[test]
+    fn hashes_render_as_lowercase_hex() {
+        use sha1::{Digest, Sha1};
+
+        assert_eq!(to_hex(&Sha1::digest(b"abc")), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        assert_eq!(to_hex(&[0x00, 0x0f, 0xab, 0xff]), "000fabff");
+    }
 }
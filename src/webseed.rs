@@ -0,0 +1,195 @@
+use std::time::{Duration, Instant};
+use std::error::Error;
+use std::{fmt, thread};
+use reqwest::StatusCode;
+use reqwest::header::RANGE;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use log::{info, warn, error};
+use crate::client::Client;
+use crate::torrent::{Torrent, Piece, FileInfo};
+
+// HTTP seeding (BEP 19), pieces are fetched with range requests from servers
+// hosting the torrent files. Only used while the peers aren't sending anything.
+pub struct WebSeeder {
+    seeds: Vec<WebSeed>,
+    http: reqwest::blocking::Client
+}
+
+// One server, with the URL of every file and the range it takes in the torrent data
+struct WebSeed {
+    url: String,
+    files: Vec<(String, u64, u64)>
+}
+
+impl WebSeeder {
+    const TIMEOUT: Duration = Duration::from_secs(60);
+    // The peers are stalled when no block arrived for this long
+    const STALL_TIMEOUT: Duration = Duration::from_secs(30);
+    const POLL_INTERVAL: Duration = Duration::from_secs(1);
+    // Wait after every web seed failed a piece, so a broken server isn't hammered
+    const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+    pub fn new(torrent: &Torrent) -> reqwest::Result<WebSeeder> {
+        let files = torrent.files();
+        let seeds = torrent.web_seeds.iter()
+            .map(|url| WebSeed::new(url, &files))
+            .collect();
+        let http = reqwest::blocking::Client::builder()
+            .timeout(Self::TIMEOUT)
+            .build()?;
+
+        Ok(WebSeeder { seeds, http })
+    }
+
+    // Downloads pieces from the web seeds whenever the peers stall, until the client is done,
+    // shutting down or over its download quota
+    pub fn run(&self, client: &Client) {
+        let mut blocks_received = client.stats().blocks_received;
+        let mut last_block = Instant::now();
+
+        while !client.is_done() && !client.is_shutting_down() && !client.is_over_quota() {
+            let received = client.stats().blocks_received;
+
+            if received != blocks_received {
+                blocks_received = received;
+                last_block = Instant::now();
+            }
+
+            if last_block.elapsed() < Self::STALL_TIMEOUT {
+                thread::sleep(Self::POLL_INTERVAL);
+                continue;
+            }
+
+            let piece = match client.get_piece_queue().pop_front() {
+                Some(piece) => piece,
+                None => {
+                    thread::sleep(Self::POLL_INTERVAL);
+                    continue;
+                }
+            };
+
+            match self.fetch(&piece) {
+                Some(data) => {
+                    client.get_traffic().add_received(data.len() as u64);
+
+                    match client.complete_piece(&piece, data) {
+                        Ok(done_pieces) => {
+                            info!("Piece {} finished from a web seed. Pieces done: {} / {}",
+                                  piece.index, done_pieces, client.torrent.total_pieces);
+                        },
+                        Err(e) => {
+                            error!("Error writing piece {}: {}", piece.index, e);
+                            client.get_piece_queue().push_back(piece);
+                        }
+                    }
+                },
+                None => {
+                    client.get_piece_queue().push_back(piece);
+                    thread::sleep(Self::RETRY_INTERVAL);
+                }
+            }
+        }
+    }
+
+    // Tries every web seed in turn until one sends the piece
+    fn fetch(&self, piece: &Piece) -> Option<Vec<u8>> {
+        for seed in &self.seeds {
+            match self.fetch_from(seed, piece) {
+                Ok(data) => return Some(data),
+                Err(e) => {
+                    warn!("Error downloading piece {} from {}: {}", piece.index, seed.url, e);
+                }
+            }
+        }
+
+        None
+    }
+
+    // A piece can span several files, each part is requested from its own file
+    fn fetch_from(&self, seed: &WebSeed, piece: &Piece) -> Result<Vec<u8>, WebSeedError> {
+        let begin = piece.begin;
+        let end = begin + piece.length as u64;
+        let mut data = Vec::with_capacity(piece.length as usize);
+
+        for (url, file_begin, file_end) in seed.files.iter().filter(|&&(_, file_begin, file_end)| file_begin < end && begin < file_end) {
+            let from = begin.max(*file_begin) - file_begin;
+            let to = end.min(*file_end) - file_begin;
+            let mut res = self.http.get(url)
+                .header(RANGE, format!("bytes={}-{}", from, to - 1))
+                .send()?;
+
+            // a server ignoring the range would send the whole file
+            if res.status() != StatusCode::PARTIAL_CONTENT {
+                return Err(WebSeedError::BadResponse(format!("Server answered with {}", res.status())));
+            }
+
+            let filled = data.len();
+
+            res.copy_to(&mut data)?;
+
+            if (data.len() - filled) as u64 != to - from {
+                return Err(WebSeedError::BadResponse(format!("Expected {} bytes but got {}", to - from, data.len() - filled)));
+            }
+        }
+
+        if piece.verify(&data).is_err() {
+            return Err(WebSeedError::WrongHash);
+        }
+
+        Ok(data)
+    }
+}
+
+impl WebSeed {
+    // A single-file torrent's URL points at the file itself unless it ends with a
+    // slash, otherwise the file paths, starting with the torrent name, are appended
+    fn new(url: &str, files: &[FileInfo]) -> WebSeed {
+        let files = files.iter()
+            .map(|file| {
+                let file_url = if file.path.iter().count() == 1 && !url.ends_with('/') {
+                    url.to_string()
+                } else {
+                    let path = file.path.iter()
+                        .map(|component| utf8_percent_encode(&component.to_string_lossy(), NON_ALPHANUMERIC).to_string())
+                        .collect::<Vec<String>>()
+                        .join("/");
+
+                    format!("{}/{}", url.trim_end_matches('/'), path)
+                };
+
+                (file_url, file.offset, file.end())
+            })
+            .collect();
+
+        WebSeed {
+            url: url.to_string(),
+            files
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum WebSeedError {
+    RequestError(reqwest::Error),
+    BadResponse(String),
+    WrongHash
+}
+
+impl fmt::Display for WebSeedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::RequestError(e) =>
+                write!(f, "{}", e),
+            Self::BadResponse(reason) =>
+                write!(f, "Bad response: {}", reason),
+            Self::WrongHash =>
+                write!(f, "Piece doesn't match its hash")
+        }
+    }
+}
+impl From<reqwest::Error> for WebSeedError {
+    fn from(err: reqwest::Error) -> Self {
+        Self::RequestError(err)
+    }
+}
+impl Error for WebSeedError {}
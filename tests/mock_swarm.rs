@@ -0,0 +1,187 @@
+// Downloads a small torrent end to end from a mock tracker and a mock peer
+// listening on loopback, then checks the output byte for byte.
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Ipv4Addr, TcpListener};
+use std::path::PathBuf;
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use byteorder::{BigEndian, ByteOrder};
+use bittorrent_client::client::{Client, TrackerEvent};
+use bittorrent_client::config::{DownloadConfig, DownloadOptions};
+use bittorrent_client::connection::Connection;
+use bittorrent_client::download_worker::DownloaderWorker;
+use bittorrent_client::message::Message;
+use bittorrent_client::torrent::Torrent;
+
+const PIECE_LENGTH: u32 = 16 * 1024;
+const MOCK_PEER_ID: &[u8; 20] = b"-MK0001-mockpeer0001";
+
+// Not a multiple of the piece length, so the last piece is shorter
+fn test_data() -> Vec<u8> {
+    (0..40_000u32).map(|i| (i * 7 % 251) as u8).collect()
+}
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("bittorrent-client-{}-{}", name, process::id()));
+
+    fs::create_dir_all(&dir).unwrap();
+
+    dir
+}
+
+// Answers one announce with a compact peer list holding only the mock peer
+fn mock_tracker(listener: TcpListener, peer_port: u16) {
+    let (stream, _) = listener.accept().unwrap();
+    let mut reader = BufReader::new(&stream);
+    let mut line = String::new();
+
+    while reader.read_line(&mut line).unwrap() > 0 && line != "\r\n" {
+        line.clear();
+    }
+
+    let mut peers = vec![127, 0, 0, 1, 0, 0];
+
+    BigEndian::write_u16(&mut peers[4..], peer_port);
+
+    let mut body = b"d8:intervali1800e5:peers6:".to_vec();
+
+    body.extend(&peers);
+    body.push(b'e');
+
+    write!(&stream, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len()).unwrap();
+    (&stream).write_all(&body).unwrap();
+}
+
+// A seed of `data`: handshakes, sends a full bitfield, or a Have per piece when
+// `bitfield` is false, unchokes once the client is interested and answers every
+// request, until the client disconnects
+fn mock_peer(listener: TcpListener, info_hash: Vec<u8>, data: Vec<u8>, piece_count: u32, bitfield: bool) -> io::Result<()> {
+    let (mut stream, _) = listener.accept()?;
+    let mut handshake = [0; 68];
+
+    stream.read_exact(&mut handshake)?;
+    assert_eq!(&handshake[28..48], &info_hash[..]);
+
+    stream.write_all(&handshake[..48])?;
+    stream.write_all(MOCK_PEER_ID)?;
+
+    if bitfield {
+        let mut bitfield = vec![0; (piece_count as usize).div_ceil(8)];
+
+        for index in 0..piece_count as usize {
+            bitfield[index / 8] |= 0x80 >> (index % 8);
+        }
+
+        stream.write_all(&Message::Bitfield(bitfield).serialize())?;
+    } else {
+        // a peer that started with no pieces can skip the bitfield
+        for index in 0..piece_count {
+            stream.write_all(&Message::Have(index).serialize())?;
+        }
+    }
+
+    loop {
+        let mut len = [0; 4];
+
+        match stream.read_exact(&mut len) {
+            Ok(()) => {},
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof || e.kind() == io::ErrorKind::ConnectionReset => return Ok(()),
+            Err(e) => return Err(e)
+        }
+
+        let mut body = vec![0; BigEndian::read_u32(&len) as usize];
+
+        stream.read_exact(&mut body)?;
+
+        match Message::try_from(&body[..]).unwrap() {
+            Message::Interested => stream.write_all(&Message::Unchoke.serialize())?,
+            Message::Request(index, begin, length) => {
+                let offset = (index * PIECE_LENGTH + begin) as usize;
+                let block = data[offset..offset + length as usize].to_vec();
+
+                stream.write_all(&Message::Piece(index, begin, block).serialize())?;
+            },
+            _ => {}
+        }
+    }
+}
+
+// Downloads through the mock tracker from a mock seed, checking the file written
+fn download_from_mock_swarm(name: &str, bitfield: bool) {
+    let data = test_data();
+    let source_dir = temp_dir(&format!("{}-source", name));
+    let out_dir = temp_dir(&format!("{}-out", name));
+    let source = source_dir.join("data.bin");
+
+    fs::write(&source, &data).unwrap();
+
+    let tracker = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let peer = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let announce = format!("http://{}/announce", tracker.local_addr().unwrap());
+    let peer_port = peer.local_addr().unwrap().port();
+    let torrent = Torrent::from_bytes(&Torrent::create_from_path(&source, PIECE_LENGTH, &announce).unwrap()).unwrap();
+
+    let tracker = thread::spawn(move || mock_tracker(tracker, peer_port));
+    let (info_hash, seeded, piece_count) = (torrent.info_hash.clone(), data.clone(), torrent.piece_count());
+    let peer = thread::spawn(move || mock_peer(peer, info_hash, seeded, piece_count, bitfield));
+
+    let options = DownloadOptions { out_dir: out_dir.clone(), ..DownloadOptions::default() };
+    let client = Arc::new(Client::new(&torrent, &options, DownloadConfig::default()).unwrap());
+    let response = client.send_tracker_request(&torrent, Some(TrackerEvent::Started)).unwrap();
+
+    tracker.join().unwrap();
+    assert_eq!(response.peers.len(), 1);
+
+    let conn = Connection::new(&client, response.peers[0]).unwrap();
+
+    DownloaderWorker::new(client.clone(), conn).run().unwrap();
+    peer.join().unwrap().unwrap();
+
+    assert!(client.is_done());
+
+    client.flush_write_cache().unwrap();
+    client.finish_files().unwrap();
+    client.sync_file().unwrap();
+
+    assert_eq!(fs::read(&client.out_path).unwrap(), data);
+
+    fs::remove_dir_all(source_dir).unwrap();
+    fs::remove_dir_all(out_dir).unwrap();
+}
+
+#[test]
+fn downloads_from_a_mock_swarm() {
+    download_from_mock_swarm("mock", true);
+}
+
+#[test]
+fn downloads_from_a_peer_without_bitfield() {
+    download_from_mock_swarm("mock-have", false);
+}
+
+// The async announce runs on the caller's tokio 0.2 runtime
+#[tokio::test]
+async fn announces_without_blocking() {
+    let tracker = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let announce = format!("http://{}/announce", tracker.local_addr().unwrap());
+    let dir = temp_dir("mock-async");
+    let source = dir.join("data.bin");
+
+    fs::write(&source, test_data()).unwrap();
+
+    let torrent = Torrent::from_bytes(&Torrent::create_from_path(&source, PIECE_LENGTH, &announce).unwrap()).unwrap();
+    let tracker = thread::spawn(move || mock_tracker(tracker, 6881));
+    let client = Client::new_in_memory(&torrent, DownloadConfig::default()).unwrap();
+    let response = client.send_tracker_request_async(&torrent, Some(TrackerEvent::Started)).await.unwrap();
+
+    tracker.join().unwrap();
+
+    assert_eq!(response.interval, 1800);
+    assert_eq!(response.peers.len(), 1);
+    assert_eq!(std::net::SocketAddr::from(response.peers[0]), "127.0.0.1:6881".parse().unwrap());
+
+    fs::remove_dir_all(dir).unwrap();
+}